@@ -0,0 +1,173 @@
+use crate::http::*;
+use std::{any::Any, sync::Arc};
+
+/// Decides whether a validated handshake may open a connection, before the 101 is sent. Set with
+/// `WebSocketServer::authorize`.
+pub(crate) type Authorize =
+    dyn Fn(&HandshakeRequest) -> Result<AcceptOptions, Rejection> + Send + Sync;
+
+/// How to accept a connection `WebSocketServer::authorize` let in
+#[derive(Clone, Default)]
+pub struct AcceptOptions {
+    pub(crate) user_data: Option<Arc<dyn Any + Send + Sync>>,
+    /// Extra headers for the 101, in the order they were added
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl AcceptOptions {
+    pub fn new() -> AcceptOptions {
+        AcceptOptions::default()
+    }
+
+    /// Attach data to the connection, e.g. the user its token belonged to, which handlers get
+    /// back through `ServerHandle::user_data`
+    pub fn with_user_data<T: Any + Send + Sync>(mut self, data: T) -> AcceptOptions {
+        self.user_data = Some(Arc::new(data));
+        self
+    }
+
+    /// Add a header to the 101 Switching Protocols response, e.g. `Set-Cookie` or a trace ID.
+    /// Headers the handshake depends on, like `Sec-WebSocket-Accept`, can't be set, and a name or
+    /// value that would break the response fails the handshake with a 500 instead.
+    pub fn with_header(mut self, name: &str, value: &str) -> AcceptOptions {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// The response a handshake `WebSocketServer::authorize` turned away gets
+/// e.g. `Rejection::unauthorized().with_header("WWW-Authenticate", "Bearer")`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Rejection {
+    /// Reject with a client or server error status. Any other status gets a 500 sent instead.
+    pub fn new(status: u16) -> Rejection {
+        Rejection {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    /// 401, for a request without valid credentials
+    pub fn unauthorized() -> Rejection {
+        Rejection::new(401)
+    }
+
+    /// 403, for credentials that don't allow this connection
+    pub fn forbidden() -> Rejection {
+        Rejection::new(403)
+    }
+
+    /// Add a header to the response. `Connection`, `Content-Length` and `Transfer-Encoding` are
+    /// up to the server, and a name or value that would break the response gets a 500 sent
+    /// instead.
+    pub fn with_header(mut self, name: &str, value: &str) -> Rejection {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sent as plain text unless a Content-Type header says otherwise
+    pub fn with_body(mut self, body: &str) -> Rejection {
+        self.body = body.to_string();
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// The response to send, or why its status or headers aren't allowed
+    pub(crate) fn into_response(self) -> Result<Response, String> {
+        if !(400..=599).contains(&self.status) {
+            return Err(format!(
+                "Rejection status must be from 400 to 599, not {}",
+                self.status
+            ));
+        }
+        Response::new(self.status)
+            .with_body(&self.body)
+            .with_custom_headers(&self.headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejection_response() {
+        let rejection = Rejection::unauthorized()
+            .with_header("WWW-Authenticate", "Bearer realm=\"rhubarb\"")
+            .with_body("Missing token");
+        assert_eq!(rejection.status(), 401);
+        assert_eq!(
+            String::from_utf8(rejection.into_response().unwrap().encode()).unwrap(),
+            "HTTP/1.1 401 Unauthorized\r\n\
+             WWW-Authenticate: Bearer realm=\"rhubarb\"\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 13\r\n\
+             Connection: close\r\n\
+             \r\n\
+             Missing token"
+        );
+
+        let json = Rejection::forbidden()
+            .with_header("Content-Type", "application/json")
+            .with_body("{}");
+        assert_eq!(
+            String::from_utf8(json.into_response().unwrap().encode()).unwrap(),
+            "HTTP/1.1 403 Forbidden\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: 2\r\n\
+             Connection: close\r\n\
+             \r\n\
+             {}"
+        );
+    }
+
+    #[test]
+    fn header_injection() {
+        let rejection = Rejection::forbidden().with_header("X-Reason", "a\r\n\r\n<html>");
+        assert_eq!(
+            rejection.into_response(),
+            Err(String::from(
+                "Response header X-Reason contains a CR, LF or NUL"
+            ))
+        );
+        assert!(Rejection::forbidden()
+            .with_header("Content-Length", "0")
+            .into_response()
+            .is_err());
+    }
+
+    #[test]
+    fn rejection_status() {
+        for status in [101, 200, 302, 600] {
+            assert_eq!(
+                Rejection::new(status).into_response(),
+                Err(format!(
+                    "Rejection status must be from 400 to 599, not {}",
+                    status
+                ))
+            );
+        }
+        assert!(Rejection::new(429).into_response().is_ok());
+    }
+
+    #[test]
+    fn user_data() {
+        let options = AcceptOptions::new().with_user_data(String::from("alice"));
+        let data = options.user_data.unwrap();
+        assert_eq!(
+            data.downcast_ref::<String>().map(|s| s.as_str()),
+            Some("alice")
+        );
+        assert!(data.downcast_ref::<u32>().is_none());
+    }
+}
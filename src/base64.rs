@@ -0,0 +1,101 @@
+/// The standard alphabet, https://www.rfc-editor.org/rfc/rfc4648#section-4
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Base64 encode `data` with padding
+pub(crate) fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode padded standard Base64, or `None` if `encoded` isn't exactly that. Bits left over in
+/// the last character have to be zero, so every input has one encoding.
+pub(crate) fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (i, chunk) in encoded.chunks(4).enumerate() {
+        let last = i == encoded.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut group = 0u32;
+        for c in &chunk[..4 - padding] {
+            let value = ALPHABET.iter().position(|a| a == c)? as u32;
+            group = group << 6 | value;
+        }
+        group <<= 6 * padding;
+        let bytes = &group.to_be_bytes()[1..4 - padding];
+        // the bits the padding stands in for have to be zero
+        if group & (0xFF_FF_FF >> (8 * bytes.len())) != 0 {
+            return None;
+        }
+        decoded.extend_from_slice(bytes);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc4648_vectors() {
+        // https://www.rfc-editor.org/rfc/rfc4648#section-10
+        for (plain, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(encode(plain.as_bytes()), encoded);
+            assert_eq!(decode(encoded), Some(plain.as_bytes().to_vec()));
+        }
+
+        let every_byte: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&every_byte)), Some(every_byte));
+        assert_eq!(encode(&[0xFB, 0xFF]), "+/8=");
+    }
+
+    #[test]
+    fn invalid() {
+        for bad in [
+            "Zg", "Zg=", "Zg===", "Z===", "Zg==Zg==", "Zm9v!A==", "Zh==", "Zm9=", "====", "Zm 9v",
+        ] {
+            assert_eq!(decode(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn websocket_key() {
+        // https://www.rfc-editor.org/rfc/rfc6455#section-1.3
+        let nonce = decode("dGhlIHNhbXBsZSBub25jZQ==").unwrap();
+        assert_eq!(nonce, b"the sample nonce");
+        assert_eq!(encode(&nonce), "dGhlIHNhbXBsZSBub25jZQ==");
+        let key = "dGhlIHNhbXBsZSBub25jZQ==258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+        assert_eq!(
+            encode(&crate::sha1::sha1(key.as_bytes())),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}
@@ -1,113 +1,160 @@
+use crate::base64;
+use crate::connection::*;
+use crate::extension::*;
+use crate::heartbeat::*;
+use crate::http::*;
 use crate::log::*;
+use crate::message::*;
+use crate::sha1::*;
 use crate::util::*;
-use base64ct::{Base64, Encoding};
-use sha1::{Digest, Sha1};
 use std::{
-    collections::HashMap,
-    io::{BufRead, BufReader, Write},
-    net::{Shutdown, TcpStream},
+    io::{Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    time::Duration,
 };
 
-pub(crate) struct WebSocketClient<S: Stream> {
-    stream: S,
+pub struct WebSocketClient<S: Stream> {
+    connection: Connection<S>,
+    /// Sent as the Host header, see `host_header`
+    host: String,
+    /// How often to Ping the server once connected, `None` to never
+    pub heartbeat_interval: Option<Duration>,
+    /// How long a Ping can go unanswered before the server is considered dead
+    pub heartbeat_timeout: Duration,
+    /// Subprotocols to offer the server, most preferred first
+    pub protocols: Vec<String>,
+    /// Extensions to offer the server, in the order they should be applied
+    pub extensions: Vec<Arc<dyn Extension>>,
 }
 
 impl Clone for WebSocketClient<TcpStream> {
     fn clone(&self) -> Self {
         Self {
-            stream: self.stream.try_clone().expect("cloning tcp stream"),
+            connection: self.connection.clone(),
+            host: self.host.clone(),
+            heartbeat_interval: self.heartbeat_interval,
+            heartbeat_timeout: self.heartbeat_timeout,
+            protocols: self.protocols.clone(),
+            extensions: self.extensions.clone(),
         }
     }
 }
 
 impl WebSocketClient<TcpStream> {
-    pub(crate) fn create(bind_addr: &str) -> std::io::Result<WebSocketClient<TcpStream>> {
+    pub fn create(bind_addr: &str) -> std::io::Result<WebSocketClient<TcpStream>> {
         let _stream = TcpStream::connect(bind_addr)?;
-        Ok(WebSocketClient { stream: _stream })
+        Ok(WebSocketClient {
+            connection: Connection::new(_stream, Role::Client),
+            host: host_header(bind_addr),
+            heartbeat_interval: Some(DEFAULT_HEARTBEAT_INTERVAL),
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            protocols: Vec::new(),
+            extensions: Vec::new(),
+        })
     }
 
-    pub(crate) fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
-        // TODO:
-        // client side of the protocol needs to:
-        // 1. decide if the data to send is big enough to break into frames (might opt for 16K by
-        //    default)
-        // 2. Create the correct frame sequence, with correct FIN
-        // 3. Mask each frame
-        // 4. Send the sequence
-        self.stream.write_all(data)?;
-        Ok(())
+    pub fn send_text(&mut self, text: &str) -> std::io::Result<()> {
+        self.connection.send_text(text)
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.connection.send_binary(data)
+    }
+
+    /// Start the closing handshake, see `Connection::close`
+    pub fn close(&mut self, code: u16, reason: &str) -> std::io::Result<()> {
+        self.connection.close(code, reason)
     }
 
-    pub(crate) fn recv(self) -> std::io::Result<()> {
-        let mut reader = BufReader::new(self.stream);
-        loop {
-            let recv: Vec<u8> = reader.fill_buf()?.to_vec();
-            reader.consume(recv.len());
-            let message = String::from_utf8(recv).unwrap();
-            if !message.is_empty() {
-                print!("{}", message);
+    /// Print everything the server sends until the connection closes
+    pub fn recv(mut self) -> std::io::Result<()> {
+        while let Some(message) = self.connection.recv()? {
+            match message {
+                Message::Text(text) => print!("{}", text),
+                Message::Binary(data) => print!("{}", String::from_utf8_lossy(&data)),
+                Message::Ping(_) | Message::Pong(_) | Message::Close(_) => {}
             }
         }
+        if let Some(close) = self.connection.close_frame() {
+            self.log(format!("Connection closed - {}", close), LogLevel::Info);
+        }
+        Ok(())
     }
 
-    pub(crate) fn perform_handshake(&mut self, path: String) -> std::io::Result<()> {
+    pub fn perform_handshake(&mut self, path: String) -> std::io::Result<()> {
         self.log(String::from("Performing Handshake"), LogLevel::Info);
         let (request, key) = self.create_handshake_http_request(path);
-        self.send(request.as_bytes())?;
+        self.connection.get_mut().write_all(request.as_bytes())?;
 
         // wait for response
-        let mut reader = BufReader::new(self.stream.try_clone()?);
-        let recv: Vec<u8> = reader.fill_buf()?.to_vec();
-        reader.consume(recv.len());
-
-        let response = String::from_utf8(recv).map_err(|_| {
-            self.stream
-                .shutdown(Shutdown::Both)
-                .expect("Shutdown succeeded");
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Failed to parse handshake as utf8",
-            )
-        })?;
-
-        self.validate_server_handshake(response, key).map_err(|e| {
-            self.log(format!("handshake failed: {}", e), LogLevel::Error);
-            self.stream
-                .shutdown(Shutdown::Both)
-                .expect("Shutdown succeeded");
-            std::io::Error::new(std::io::ErrorKind::InvalidData, e)
-        })?;
-
-        // TODO: after succesful upgrade, need to break off a background thread that sends
-        // ping-pongs. Ping frames should just contain some random data that the server echoes back
+        let mut parser = HeadParser::new(MAX_HEAD_LEN);
+        let mut read_buf = [0u8; 4096];
+        let (head, leftover) = loop {
+            let len = self.connection.get_mut().read(&mut read_buf)?;
+            if len == 0 {
+                return Err(
+                    self.fail_handshake(String::from("Server disconnected during handshake"))
+                );
+            }
+            match parser.feed(&read_buf[..len]) {
+                Ok(Some(parsed)) => break parsed,
+                Ok(None) => {}
+                Err(e) => return Err(self.fail_handshake(e)),
+            }
+        };
+
+        let (protocol, extensions) = self
+            .validate_server_handshake(head, key)
+            .map_err(|e| self.fail_handshake(e))?;
+
+        // the server may already have sent frames right behind its response
+        self.connection.unread(leftover);
+        self.connection.open(protocol, extensions);
+        if let Some(interval) = self.heartbeat_interval {
+            self.connection
+                .start_heartbeat(interval, self.heartbeat_timeout);
+        }
         Ok(())
     }
+
+    /// Log why the handshake failed and hang up
+    fn fail_handshake(&self, e: String) -> std::io::Error {
+        self.log(format!("handshake failed: {}", e), LogLevel::Error);
+        // the server has often hung up already after refusing us
+        _ = Stream::shutdown(self.connection.get_ref());
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl<S: Stream> WebSocketClient<S> {
+    pub fn connection(&self) -> &Connection<S> {
+        &self.connection
+    }
+
+    pub fn connection_mut(&mut self) -> &mut Connection<S> {
+        &mut self.connection
+    }
 }
 
 // NOTE: per the RFC, there's a `connecting` state for clients attempting to connect to the same
 // remote simultaneously. rhubarb in its current state doesn't allow multiple client connections
 // from one process anyway, so I'm ignoring this for now.
 impl<S: Stream> WebSocketClient<S> {
+    /// Returns the subprotocol and extensions the server picked, or a reason the handshake failed
     fn validate_server_handshake(
         &self,
-        server_response: String,
+        head: MessageHead,
         key: String,
-    ) -> Result<(), String> {
+    ) -> Result<(Option<String>, ExtensionChain), String> {
         self.log(
-            format!("Validating client handshake\n{}", server_response),
+            format!("Validating server handshake\n{}", head),
             LogLevel::Debug,
         );
 
-        let mut components = server_response.trim().split('\n');
-        // pop the http version & response code
-        let http_response = match components.next() {
-            Some(r) => r,
-            None => return Err(String::from("Handshake is not a valid HTTP response")),
-        };
-
         // validation 1 - must be 101 switching protocols
         // for rhubarb, I ignore anything else and just error
-        let mut response_components = http_response.split_whitespace();
+        let mut response_components = head.start_line.split_whitespace();
         response_components.next();
         match response_components.next() {
             Some("101") => {}
@@ -115,28 +162,25 @@ impl<S: Stream> WebSocketClient<S> {
             None => return Err(String::from("Missing response code")),
         };
 
-        let headers = components
-            .filter_map(|header| header.split_once(':'))
-            .map(|(header_name, val)| (header_name.trim().to_lowercase(), val.trim()))
-            .collect::<HashMap<_, _>>();
+        let headers = HeaderMap::from(head.headers);
 
         // validation 2 - must include "upgrade: websocket" header
         match headers.get("upgrade") {
-            Some(ug) if ug.to_lowercase() == "websocket" => {}
+            Some(_) if headers.has_token("upgrade", "websocket") => {}
             Some(_) => return Err(String::from("Requested Upgrade was not 'websocket'")),
             None => return Err(String::from("Handshake missing Upgrade header")),
         };
 
-        // validation 3 - must include "connection: upgrade" header
+        // validation 3 - must include "connection: upgrade" header, possibly among other options
         match headers.get("connection") {
-            Some(conn) if conn.to_lowercase() == "upgrade" => {}
+            Some(_) if headers.has_token("connection", "upgrade") => {}
             Some(_) => return Err(String::from("Requested Connection was not 'upgrade'")),
             None => return Err(String::from("Handshake missing Connection header")),
         };
 
         // validation 4 - key validation
-        let accept_key = match headers.get("sec-websocket-accept") {
-            Some(h) => h.trim().to_string(),
+        let accept_key = match headers.get_unique("Sec-WebSocket-Accept")? {
+            Some(h) => h.to_string(),
             None => {
                 return Err(String::from(
                     "Handshake missing Sec-WebSocket-Accept header",
@@ -144,54 +188,89 @@ impl<S: Stream> WebSocketClient<S> {
             }
         };
 
-        let hash = Sha1::digest((key + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11").as_bytes());
-        let expected_key = Base64::encode_string(&hash);
+        let hash = sha1((key + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11").as_bytes());
+        let expected_key = base64::encode(&hash);
 
         if accept_key != expected_key {
             return Err(String::from("Server key invalid"));
         }
 
-        Ok(())
+        // validation 5 - the server can only pick one of the subprotocols we offered
+        let protocol = match headers.get_unique("Sec-WebSocket-Protocol")? {
+            Some(protocol) if self.protocols.iter().any(|p| p == protocol) => {
+                Some(protocol.to_string())
+            }
+            Some(protocol) => {
+                return Err(format!(
+                    "Server picked subprotocol '{}' which was not offered",
+                    protocol
+                ))
+            }
+            None => None,
+        };
+
+        // validation 6 - and only extensions we offered
+        let extensions = match headers.get_all("sec-websocket-extensions")[..] {
+            [] => ExtensionChain::default(),
+            ref lines => confirm(&parse_extensions(&lines.join(", "))?, &self.extensions)?,
+        };
+
+        Ok((protocol, extensions))
     }
 
     /// Returns the HTTP GET request and the Sec-WebSocket-Key value created
     fn create_handshake_http_request(&self, path: String) -> (String, String) {
         let mut nonce = [0u8; 16];
-        rand::fill(&mut nonce);
-        let key = Base64::encode_string(&nonce);
+        self.connection.fill_random(&mut nonce);
+        let key = base64::encode(&nonce);
+        let protocols = match self.protocols.is_empty() {
+            true => String::new(),
+            false => format!("Sec-WebSocket-Protocol: {}\r\n", self.protocols.join(", ")),
+        };
+        let extensions = match self.extensions.is_empty() {
+            true => String::new(),
+            false => {
+                let offers: Vec<String> = self
+                    .extensions
+                    .iter()
+                    .map(|e| e.offer().to_string())
+                    .collect();
+                format!("Sec-WebSocket-Extensions: {}\r\n", offers.join(", "))
+            }
+        };
         (
             format!(
-                "GET {path} HTTP/1.1\n\
-            Host: {}\n\
-            Upgrade: websocket\n\
-            Connection: Upgrade\n\
-            Sec-WebSocket-Key: {}\n\
-            Sec-WebSocket-Protocol: rhubarb\n\
-            Sec-WebSocket-Version: 13\n
-            ",
-                self.stream.peer_addr().expect("peer address found"),
-                key
+                "GET {path} HTTP/1.1\r\n\
+            Host: {}\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: {}\r\n\
+            {protocols}\
+            {extensions}\
+            Sec-WebSocket-Version: 13\r\n\r\n",
+                self.host, key
             ),
             key,
         )
     }
 
     fn log(&self, msg: String, level: LogLevel) {
-        // NOTE: this expect is half-reasonable since if we can't get a peer addr how are we
-        // connected, but it should probably be handled more gracefully
-        log(
-            format!(
-                "{} - {msg}",
-                self.stream.peer_addr().expect("peer address found")
-            ),
-            level,
-        );
+        self.connection.log(msg, level);
     }
 }
 
+/// The Host header for a connection to `addr`, the name and port given to `create` rather than
+/// the address it resolved to, so servers that check which name they were reached by can tell.
+/// Port 80 is the default for ws:// and left out.
+/// https://www.rfc-editor.org/rfc/rfc6455#section-4.1
+fn host_header(addr: &str) -> String {
+    addr.strip_suffix(":80").unwrap_or(addr).to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::random::*;
     use std::net::{IpAddr, Ipv4Addr};
 
     struct MockStream {}
@@ -202,11 +281,36 @@ mod tests {
                 4024,
             ))
         }
+
+        fn shutdown(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn set_read_timeout(&self, _dur: Option<Duration>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn head(raw: &str) -> MessageHead {
+        let raw: String = raw
+            .lines()
+            .map(|line| format!("{}\r\n", line.trim()))
+            .collect();
+        HeadParser::new(MAX_HEAD_LEN)
+            .feed(format!("{raw}\r\n").as_bytes())
+            .unwrap()
+            .unwrap()
+            .0
     }
 
     fn make_test_client() -> WebSocketClient<MockStream> {
         WebSocketClient {
-            stream: MockStream {},
+            connection: Connection::new(MockStream {}, Role::Client),
+            host: String::from("localhost:4024"),
+            heartbeat_interval: None,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            protocols: Vec::new(),
+            extensions: Vec::new(),
         }
     }
 
@@ -215,34 +319,181 @@ mod tests {
         let client = make_test_client();
         let (_, key) = client.create_handshake_http_request(String::from("/ws"));
         let combined_key = key.clone() + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-        let hash = Sha1::digest(combined_key.as_bytes());
-        let server_key = Base64::encode_string(&hash);
+        let hash = sha1(combined_key.as_bytes());
+        let server_key = base64::encode(&hash);
         assert!(client
             .validate_server_handshake(
-                format!(
+                head(&format!(
                     "HTTP/1.1 101 Switching Protocols\n\
                 Upgrade: websocket\n\
                 Connection: Upgrade\n\
                 Sec-WebSocket-Accept: {server_key}"
-                ),
+                )),
                 key,
             )
             .is_ok())
     }
 
+    #[test]
+    fn seeded_key() {
+        let key = || {
+            let mut client = make_test_client();
+            client
+                .connection_mut()
+                .set_random(ChaCha20Rng::from_seed([5; 32]));
+            client.create_handshake_http_request(String::from("/ws")).1
+        };
+        assert_eq!(key(), key());
+        assert_eq!(base64::decode(&key()).map(|nonce| nonce.len()), Some(16));
+        assert_ne!(
+            make_test_client()
+                .create_handshake_http_request(String::from("/ws"))
+                .1,
+            key()
+        );
+    }
+
+    #[test]
+    fn protocol_negotiation() {
+        let mut client = make_test_client();
+        let (request, _) = client.create_handshake_http_request(String::from("/ws"));
+        assert!(!request.contains("Sec-WebSocket-Protocol"));
+        // nothing may follow the blank line, the server would read it as the first frame
+        assert!(request.ends_with("Sec-WebSocket-Version: 13\r\n\r\n"));
+
+        client.protocols = vec![String::from("v2.chat"), String::from("chat")];
+        let (request, key) = client.create_handshake_http_request(String::from("/ws"));
+        assert!(request.contains("Sec-WebSocket-Protocol: v2.chat, chat\r\n"));
+
+        let hash = sha1((key.clone() + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11").as_bytes());
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\n\
+            Upgrade: websocket\n\
+            Connection: Upgrade\n\
+            Sec-WebSocket-Accept: {}",
+            base64::encode(&hash)
+        );
+        assert_eq!(
+            client.validate_server_handshake(head(&response), key.clone()),
+            Ok((None, ExtensionChain::default()))
+        );
+        assert_eq!(
+            client.validate_server_handshake(
+                head(&format!("{}\nSec-WebSocket-Protocol: chat", response)),
+                key.clone()
+            ),
+            Ok((Some(String::from("chat")), ExtensionChain::default()))
+        );
+        assert_eq!(
+            client.validate_server_handshake(
+                head(&format!("{}\nSec-WebSocket-Protocol: superchat", response)),
+                key
+            ),
+            Err(String::from(
+                "Server picked subprotocol 'superchat' which was not offered"
+            ))
+        );
+    }
+
+    #[test]
+    fn extension_negotiation() {
+        let mut client = make_test_client();
+        let (request, _) = client.create_handshake_http_request(String::from("/ws"));
+        assert!(!request.contains("Sec-WebSocket-Extensions"));
+
+        client.extensions = vec![Arc::new(crate::extension::tests::Reverse)];
+        let (request, key) = client.create_handshake_http_request(String::from("/ws"));
+        assert!(request.contains("Sec-WebSocket-Extensions: x-reverse\r\n"));
+
+        let hash = sha1((key.clone() + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11").as_bytes());
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\n\
+            Upgrade: websocket\n\
+            Connection: Upgrade\n\
+            Sec-WebSocket-Accept: {}",
+            base64::encode(&hash)
+        );
+        let (_, extensions) = client
+            .validate_server_handshake(
+                head(&format!(
+                    "{}\nSec-WebSocket-Extensions: x-reverse",
+                    response
+                )),
+                key.clone(),
+            )
+            .unwrap();
+        assert_eq!(extensions.names(), vec![String::from("x-reverse")]);
+        assert_eq!(
+            client.validate_server_handshake(
+                head(&format!(
+                    "{}\nSec-WebSocket-Extensions: permessage-deflate",
+                    response
+                )),
+                key
+            ),
+            Err(String::from(
+                "Server accepted extension permessage-deflate which was not offered"
+            ))
+        );
+    }
+
+    #[test]
+    fn listed_and_repeated_headers() {
+        let client = make_test_client();
+        let (_, key) = client.create_handshake_http_request(String::from("/ws"));
+        let hash = sha1((key.clone() + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11").as_bytes());
+        let accept = base64::encode(&hash);
+
+        assert!(client
+            .validate_server_handshake(
+                head(&format!(
+                    "HTTP/1.1 101 Switching Protocols
+                    upgrade: WebSocket
+                    connection: keep-alive, UPGRADE
+                    sec-websocket-accept: {accept}"
+                )),
+                key.clone()
+            )
+            .is_ok());
+        assert!(client
+            .validate_server_handshake(
+                head(&format!(
+                    "HTTP/1.1 101 Switching Protocols
+                    Upgrade: websocket
+                    Connection: keep-alive
+                    Connection: Upgrade
+                    Sec-WebSocket-Accept: {accept}"
+                )),
+                key.clone()
+            )
+            .is_ok());
+        assert_eq!(
+            client.validate_server_handshake(
+                head(&format!(
+                    "HTTP/1.1 101 Switching Protocols
+                    Upgrade: websocket
+                    Connection: Upgrade
+                    Sec-WebSocket-Accept: {accept}
+                    Sec-WebSocket-Accept: {accept}"
+                )),
+                key
+            ),
+            Err(String::from(
+                "Handshake contains more than one Sec-WebSocket-Accept header"
+            ))
+        );
+    }
+
     #[test]
     fn malformed_response() {
         let client = make_test_client();
 
         assert_eq!(
-            client.validate_server_handshake(String::from(""), String::from("")),
+            client.validate_server_handshake(head("HTTP/1.1"), String::from("")),
             Err(String::from("Missing response code"))
         );
         assert_eq!(
-            client.validate_server_handshake(
-                String::from("HTTP/1.1 400 Bad Request"),
-                String::from("")
-            ),
+            client.validate_server_handshake(head("HTTP/1.1 400 Bad Request"), String::from("")),
             Err(String::from("Invalid response code 400"))
         );
     }
@@ -253,14 +504,14 @@ mod tests {
 
         assert_eq!(
             client.validate_server_handshake(
-                String::from("HTTP/1.1 101 Switching Protocols"),
+                head("HTTP/1.1 101 Switching Protocols"),
                 String::from("")
             ),
             Err(String::from("Handshake missing Upgrade header"))
         );
         assert_eq!(
             client.validate_server_handshake(
-                String::from(
+                head(
                     "HTTP/1.1 101 Switching Protocols\n\
                     Upgrade: not-websocket"
                 ),
@@ -276,7 +527,7 @@ mod tests {
 
         assert_eq!(
             client.validate_server_handshake(
-                String::from(
+                head(
                     "HTTP/1.1 101 Switching Protocols\n\
                     Upgrade: websocket"
                 ),
@@ -286,7 +537,7 @@ mod tests {
         );
         assert_eq!(
             client.validate_server_handshake(
-                String::from(
+                head(
                     "HTTP/1.1 101 Switching Protocols\n\
                     Upgrade: websocket\n\
                     Connection: not upgrade"
@@ -303,7 +554,7 @@ mod tests {
 
         assert_eq!(
             client.validate_server_handshake(
-                String::from(
+                head(
                     "HTTP/1.1 101 Switching Protocols\n\
                     Upgrade: websocket\n\
                     Connection: upgrade\n"
@@ -316,7 +567,7 @@ mod tests {
         );
         assert_eq!(
             client.validate_server_handshake(
-                String::from(
+                head(
                     "HTTP/1.1 101 Switching Protocols\n\
                     Upgrade: websocket\n\
                     Connection: upgrade\n\
@@ -327,4 +578,15 @@ mod tests {
             Err(String::from("Server key invalid"))
         );
     }
+
+    #[test]
+    fn host_header_value() {
+        assert_eq!(host_header("example.com:80"), "example.com");
+        assert_eq!(host_header("example.com:8080"), "example.com:8080");
+        assert_eq!(host_header("[::1]:80"), "[::1]");
+        assert_eq!(host_header("[::1]:4024"), "[::1]:4024");
+
+        let (request, _) = make_test_client().create_handshake_http_request(String::from("/"));
+        assert!(request.contains("\r\nHost: localhost:4024\r\n"));
+    }
 }
@@ -0,0 +1,135 @@
+use crate::frame::FrameError;
+use std::{fmt, time::Duration};
+
+/// How long to wait for the peer's Close after sending ours before dropping the TCP connection
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Status code and reason carried by a Close frame
+/// https://www.rfc-editor.org/rfc/rfc6455#section-5.5.1
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+/// Whether `code` may be put on the wire in a Close frame. 1004 is reserved, 1005, 1006 and 1015
+/// are only for reporting locally, 1016-2999 are reserved for future revisions of the protocol, and
+/// 3000-4999 belong to libraries and applications.
+/// https://www.rfc-editor.org/rfc/rfc6455#section-7.4
+fn is_sendable_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+impl CloseFrame {
+    /// A Close frame that's valid to send. The reason has to fit in the 125 byte control frame
+    /// payload alongside the 2 byte code.
+    pub fn new(code: u16, reason: &str) -> std::io::Result<CloseFrame> {
+        if !is_sendable_code(code) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not a valid close code to send", code),
+            ));
+        }
+        if reason.len() > 123 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "close reason is longer than 123 bytes",
+            ));
+        }
+        Ok(CloseFrame {
+            code,
+            reason: reason.to_string(),
+        })
+    }
+
+    /// Parse a received Close frame's payload. An empty payload means the peer gave no status.
+    pub(crate) fn parse(payload: &[u8]) -> Result<Option<CloseFrame>, FrameError> {
+        let (code, reason) = match payload {
+            [] => return Ok(None),
+            [hi, lo, reason @ ..] => (u16::from_be_bytes([*hi, *lo]), reason),
+            _ => return Err(FrameError::InvalidClosePayload),
+        };
+        if !is_sendable_code(code) {
+            return Err(FrameError::InvalidCloseCode(code));
+        }
+        let reason = std::str::from_utf8(reason).map_err(|_| FrameError::InvalidUtf8)?;
+        Ok(Some(CloseFrame {
+            code,
+            reason: reason.to_string(),
+        }))
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut payload = self.code.to_be_bytes().to_vec();
+        payload.extend_from_slice(self.reason.as_bytes());
+        payload
+    }
+}
+
+impl fmt::Display for CloseFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.reason.is_empty() {
+            write!(f, "{}", self.code)
+        } else {
+            write!(f, "{} {}", self.code, self.reason)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_parse() {
+        let close = CloseFrame::new(1000, "bye").unwrap();
+        assert_eq!(close.encode(), vec![0x03, 0xE8, b'b', b'y', b'e']);
+        assert_eq!(CloseFrame::parse(&close.encode()), Ok(Some(close)));
+
+        let close = CloseFrame::new(4000, "").unwrap();
+        assert_eq!(CloseFrame::parse(&close.encode()), Ok(Some(close)));
+    }
+
+    #[test]
+    fn parse_empty() {
+        assert_eq!(CloseFrame::parse(&[]), Ok(None));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(
+            CloseFrame::parse(&[0x03]),
+            Err(FrameError::InvalidClosePayload)
+        );
+        for code in [0u16, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000, 65535] {
+            assert_eq!(
+                CloseFrame::parse(&code.to_be_bytes()),
+                Err(FrameError::InvalidCloseCode(code)),
+                "{}",
+                code
+            );
+        }
+        assert_eq!(
+            CloseFrame::parse(&[0x03, 0xE8, 0xFF]),
+            Err(FrameError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn valid_codes() {
+        for code in [
+            1000u16, 1001, 1002, 1003, 1007, 1008, 1009, 1010, 1011, 3000, 4999,
+        ] {
+            assert!(CloseFrame::parse(&code.to_be_bytes()).unwrap().is_some());
+            assert!(CloseFrame::new(code, "").is_ok());
+        }
+    }
+
+    #[test]
+    fn new_invalid() {
+        assert!(CloseFrame::new(1005, "").is_err());
+        assert!(CloseFrame::new(1006, "").is_err());
+        assert!(CloseFrame::new(1000, &"a".repeat(123)).is_ok());
+        assert!(CloseFrame::new(1000, &"a".repeat(124)).is_err());
+    }
+}
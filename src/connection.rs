@@ -0,0 +1,862 @@
+use crate::close::*;
+use crate::extension::*;
+use crate::frame::*;
+use crate::heartbeat::*;
+use crate::log::*;
+use crate::message::*;
+use crate::random::*;
+use crate::util::*;
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// Messages larger than this are split into multiple frames by default
+pub(crate) const DEFAULT_FRAGMENT_SIZE: usize = 16 * 1024;
+
+/// Bytes asked of the stream per read
+const READ_SIZE: usize = 16 * 1024;
+
+/// https://www.rfc-editor.org/rfc/rfc6455#section-4.1 and
+/// https://www.rfc-editor.org/rfc/rfc6455#section-7
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Opening handshake hasn't completed yet
+    Connecting,
+    /// Handshake done, messages can flow both ways
+    Open,
+    /// A Close has been sent and we're waiting on the peer's
+    Closing,
+    /// Closing handshake done, or the TCP connection was dropped
+    Closed,
+}
+
+/// Which end of the connection this is, which decides the direction masking goes in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Client,
+    Server,
+}
+
+/// State shared between a connection and all of its clones
+struct Shared {
+    state: ConnectionState,
+    close_sent: bool,
+    close_received: bool,
+    /// Status the connection ended with, see https://www.rfc-editor.org/rfc/rfc6455#section-7.1.5
+    close_frame: Option<CloseFrame>,
+    heartbeat: Option<Arc<Heartbeat>>,
+}
+
+/// One WebSocket connection, used by both the client and the server once the stream is connected.
+/// Clones share the stream and the protocol state, so any of them can send, but only one of them
+/// should be reading with `recv`.
+pub struct Connection<S: Stream> {
+    stream: S,
+    role: Role,
+    /// Looked up once up front, the stream can't tell us anymore after it's shut down
+    peer_addr: Option<SocketAddr>,
+    /// Subprotocol agreed on in the handshake
+    protocol: Option<String>,
+    /// Largest payload put into a single frame when sending a message
+    pub fragment_size: usize,
+    /// Held for the whole of a message's frame sequence, so that messages sent from clones of
+    /// this connection never interleave their fragments
+    send_lock: Arc<Mutex<()>>,
+    shared: Arc<Mutex<Shared>>,
+    /// Extensions agreed on in the handshake, shared by the sending and receiving halves
+    extensions: Arc<Mutex<ExtensionChain>>,
+    /// Source of masking keys and Ping payloads, shared with every clone
+    random: Arc<Mutex<Box<dyn RandomSource>>>,
+    decoder: FrameDecoder,
+    assembler: MessageAssembler,
+    /// Frames decoded off the stream but not handed to the assembler yet
+    frames: VecDeque<WebSocketFrame>,
+    /// Bytes that arrived along with the opening handshake, decoded before reading any more
+    unread: Vec<u8>,
+}
+
+impl Clone for Connection<TcpStream> {
+    fn clone(&self) -> Self {
+        let mut decoder = FrameDecoder::new(Some(self.role == Role::Server));
+        decoder.allow_rsv(self.chain().rsv_bits());
+        Self {
+            stream: self.stream.try_clone().expect("cloning tcp stream"),
+            role: self.role,
+            peer_addr: self.peer_addr,
+            protocol: self.protocol.clone(),
+            fragment_size: self.fragment_size,
+            send_lock: self.send_lock.clone(),
+            shared: self.shared.clone(),
+            extensions: self.extensions.clone(),
+            random: self.random.clone(),
+            decoder,
+            assembler: MessageAssembler::with_extensions(self.extensions.clone()),
+            frames: VecDeque::new(),
+            unread: Vec::new(),
+        }
+    }
+}
+
+impl<S: Stream> Connection<S> {
+    /// A connection in the `Connecting` state, `open` it once the handshake is done
+    pub(crate) fn new(stream: S, role: Role) -> Connection<S> {
+        let extensions = Arc::new(Mutex::new(ExtensionChain::default()));
+        Connection {
+            peer_addr: stream.peer_addr().ok(),
+            protocol: None,
+            stream,
+            role,
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            send_lock: Arc::new(Mutex::new(())),
+            shared: Arc::new(Mutex::new(Shared {
+                state: ConnectionState::Connecting,
+                close_sent: false,
+                close_received: false,
+                close_frame: None,
+                heartbeat: None,
+            })),
+            extensions: extensions.clone(),
+            random: Arc::new(Mutex::new(Box::new(SystemRandom))),
+            // servers only take masked frames, clients only take unmasked ones
+            decoder: FrameDecoder::new(Some(role == Role::Server)),
+            assembler: MessageAssembler::with_extensions(extensions),
+            frames: VecDeque::new(),
+            unread: Vec::new(),
+        }
+    }
+
+    /// The raw stream, for the opening handshake
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Hand back bytes the handshake read past its end, they're the start of the first frame
+    pub(crate) fn unread(&mut self, bytes: Vec<u8>) {
+        self.unread = bytes;
+    }
+
+    /// Finish the opening handshake, with the subprotocol and extensions it settled on
+    pub(crate) fn open(&mut self, protocol: Option<String>, extensions: ExtensionChain) {
+        self.protocol = protocol;
+        self.decoder.allow_rsv(extensions.rsv_bits());
+        *self.chain() = extensions;
+        self.shared().state = ConnectionState::Open;
+    }
+
+    /// The subprotocol the server picked from the client's Sec-WebSocket-Protocol, if any
+    /// https://www.rfc-editor.org/rfc/rfc6455#section-1.9
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Names of the extensions in use, in the order they were negotiated
+    pub fn extensions(&self) -> Vec<String> {
+        self.chain().names()
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.shared().state
+    }
+
+    /// Whether we've sent a Close frame
+    pub fn close_sent(&self) -> bool {
+        self.shared().close_sent
+    }
+
+    /// Whether the peer has sent a Close frame
+    pub fn close_received(&self) -> bool {
+        self.shared().close_received
+    }
+
+    /// The code and reason the connection closed with, once it's `Closed`. That's the status from
+    /// the peer's Close, 1005 if it carried none, or 1006 if the connection went away without one.
+    pub fn close_frame(&self) -> Option<CloseFrame> {
+        self.shared().close_frame.clone()
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.peer_addr.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotConnected, "peer address unknown")
+        })
+    }
+
+    fn shared(&self) -> MutexGuard<'_, Shared> {
+        // a poisoned lock just means another thread panicked, the state itself is still usable
+        self.shared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn chain(&self) -> MutexGuard<'_, ExtensionChain> {
+        self.extensions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Move to `Closed`, recording `close_frame` as the final status unless one is already set
+    fn finish(&self, close_frame: CloseFrame) {
+        let mut shared = self.shared();
+        shared.state = ConnectionState::Closed;
+        shared.close_frame.get_or_insert(close_frame);
+        if let Some(heartbeat) = shared.heartbeat.take() {
+            heartbeat.stop();
+        }
+        _ = self.stream.shutdown();
+    }
+
+    fn mask_key(&self) -> Option<[u8; 4]> {
+        match self.role {
+            Role::Client => Some(new_mask_key(&mut self.random.clone())),
+            Role::Server => None,
+        }
+    }
+
+    /// Replace where masking keys, handshake nonces and Ping payloads come from, for this
+    /// connection and all of its clones. A seeded `ChaCha20Rng` makes them reproducible.
+    pub fn set_random(&mut self, random: impl RandomSource + 'static) {
+        *self.random.lock().unwrap_or_else(|e| e.into_inner()) = Box::new(random);
+    }
+
+    pub(crate) fn fill_random(&self, buf: &mut [u8]) {
+        self.random.clone().fill(buf)
+    }
+
+    pub(crate) fn log(&self, msg: String, level: LogLevel) {
+        match self.peer_addr {
+            Some(addr) => log(format!("{addr} - {msg}"), level),
+            None => log(format!("unknown peer - {msg}"), level),
+        }
+    }
+}
+
+impl<S: Stream + Write> Connection<S> {
+    pub fn send_text(&mut self, text: &str) -> std::io::Result<()> {
+        self.send_message(WebSocketOpCode::Text, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.send_message(WebSocketOpCode::Binary, data)
+    }
+
+    /// Fragments, masks if we're the client, and sends a whole message
+    fn send_message(&mut self, opcode: WebSocketOpCode, data: &[u8]) -> std::io::Result<()> {
+        // a poisoned lock just means another sender panicked mid-write, nothing to recover here
+        let _guard = self.send_lock.lock().unwrap_or_else(|e| e.into_inner());
+        match self.state() {
+            ConnectionState::Open => {}
+            ConnectionState::Connecting => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "opening handshake has not completed",
+                ))
+            }
+            ConnectionState::Closing | ConnectionState::Closed => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "connection is closing",
+                ))
+            }
+        }
+
+        // extensions keep state from one message to the next, so they have to see messages in
+        // the same order they go out on the wire
+        let mut message = ExtensionMessage {
+            text: opcode == WebSocketOpCode::Text,
+            rsv: 0,
+            data: data.to_vec(),
+        };
+        self.chain().outgoing(&mut message)?;
+        let mut random = self.random.clone();
+        let mut frames = WebSocketFrame::fragment(
+            opcode,
+            &message.data,
+            self.fragment_size,
+            match self.role {
+                Role::Client => Some(&mut random),
+                Role::Server => None,
+            },
+        );
+        frames[0].rsv = message.rsv;
+        for frame in frames {
+            self.stream.write_all(&frame.encode())?;
+        }
+        Ok(())
+    }
+
+    /// Sends a single control frame. Nothing is sent after a Close, not even another Close.
+    fn send_control(&mut self, opcode: WebSocketOpCode, payload: Vec<u8>) -> std::io::Result<()> {
+        let frame = WebSocketFrame::new_bin(true, opcode, payload, self.mask_key());
+        let _guard = self.send_lock.lock().unwrap_or_else(|e| e.into_inner());
+        if self.close_sent() {
+            return Ok(());
+        }
+        if opcode == WebSocketOpCode::Close {
+            let mut shared = self.shared();
+            shared.close_sent = true;
+            if shared.state == ConnectionState::Open {
+                shared.state = ConnectionState::Closing;
+            }
+        }
+        self.stream.write_all(&frame.encode())
+    }
+
+    /// Start the closing handshake, https://www.rfc-editor.org/rfc/rfc6455#section-7.1.2
+    /// `recv` drops the connection once the peer answers, or after `CLOSE_TIMEOUT` if it never
+    /// does. No more messages can be sent afterwards.
+    pub fn close(&mut self, code: u16, reason: &str) -> std::io::Result<()> {
+        let close = CloseFrame::new(code, reason)?;
+        self.send_control(WebSocketOpCode::Close, close.encode())?;
+        self.stream.set_read_timeout(Some(CLOSE_TIMEOUT))
+    }
+
+    /// Sends a Close frame with the status code matching `err` and drops the connection
+    fn fail(&mut self, err: &FrameError) {
+        self.log(format!("Failing connection - {}", err), LogLevel::Warning);
+        // extensions pick their own code and reason, which still have to fit a Close frame
+        let mut reason = err.to_string();
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason.truncate(end);
+        let close = CloseFrame::new(err.close_code(), &reason)
+            .or_else(|_| CloseFrame::new(1011, &reason))
+            .expect("1011 with a short enough reason is valid");
+        _ = self.send_control(WebSocketOpCode::Close, close.encode());
+        self.finish(CloseFrame {
+            code: 1006,
+            reason: err.to_string(),
+        });
+    }
+}
+
+impl<S: Stream + Read + Write> Connection<S> {
+    /// Wait for the next message. Pings are answered and a Close is echoed before they're handed
+    /// back, so the application only needs to look at them if it's interested. Returns `None` once
+    /// the connection is closed.
+    pub fn recv(&mut self) -> std::io::Result<Option<Message>> {
+        let mut read_buf = vec![0u8; READ_SIZE];
+        loop {
+            while let Some(frame) = self.frames.pop_front() {
+                match self.assembler.push(frame) {
+                    Ok(Some(message)) => {
+                        // a Pong or Close echo that can't be written means the stream is dead
+                        if let Err(e) = self.handle_control(&message) {
+                            self.finish(CloseFrame {
+                                code: 1006,
+                                reason: e.to_string(),
+                            });
+                            return Err(e);
+                        }
+                        return Ok(Some(message));
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        self.fail(&e);
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+                    }
+                }
+            }
+
+            if self.state() == ConnectionState::Closed {
+                return Ok(None);
+            }
+
+            let read = match self.unread.is_empty() {
+                true => self.stream.read(&mut read_buf),
+                false => {
+                    let len = self.unread.len().min(read_buf.len());
+                    read_buf[..len].copy_from_slice(&self.unread[..len]);
+                    self.unread.drain(..len);
+                    Ok(len)
+                }
+            };
+            let len = match read {
+                Ok(len) => len,
+                // the read timeout is only set once we're waiting on the peer's Close
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) && self.close_sent() =>
+                {
+                    self.log(
+                        String::from("Peer never answered Close, dropping connection"),
+                        LogLevel::Warning,
+                    );
+                    0
+                }
+                Err(e) => {
+                    self.finish(CloseFrame {
+                        code: 1006,
+                        reason: e.to_string(),
+                    });
+                    return Err(e);
+                }
+            };
+            if len == 0 {
+                self.finish(CloseFrame {
+                    code: 1006,
+                    reason: String::new(),
+                });
+                return Ok(None);
+            }
+
+            match self.decoder.feed(&read_buf[..len]) {
+                Ok(frames) => self.frames.extend(frames),
+                Err(e) => {
+                    self.fail(&e);
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+                }
+            }
+        }
+    }
+
+    /// The automatic half of the control frames: Pong every Ping, hand Pongs to the heartbeat, and
+    /// finish the closing handshake
+    fn handle_control(&mut self, message: &Message) -> std::io::Result<()> {
+        match message {
+            Message::Ping(data) => self.send_control(WebSocketOpCode::Pong, data.clone()),
+            Message::Pong(data) => {
+                if let Some(heartbeat) = &self.shared().heartbeat {
+                    heartbeat.pong(data);
+                }
+                Ok(())
+            }
+            Message::Close(close) => {
+                self.shared().close_received = true;
+                // echo the status back, unless this is the answer to our own Close
+                let payload = close.as_ref().map(|c| c.encode()).unwrap_or_default();
+                let result = self.send_control(WebSocketOpCode::Close, payload);
+                self.finish(close.clone().unwrap_or(CloseFrame {
+                    code: 1005,
+                    reason: String::new(),
+                }));
+                result
+            }
+            Message::Text(_) | Message::Binary(_) => Ok(()),
+        }
+    }
+}
+
+impl Connection<TcpStream> {
+    /// Breaks off a background thread that Pings the peer every `interval`, and gives up on the
+    /// connection if the peer stops answering for longer than `timeout`
+    pub(crate) fn start_heartbeat(&mut self, interval: Duration, timeout: Duration) {
+        let mut pinger = self.clone();
+        let mut closer = self.clone();
+        let heartbeat = Heartbeat::start(
+            interval,
+            timeout,
+            self.random.clone(),
+            move |payload| pinger.send_control(WebSocketOpCode::Ping, payload),
+            move || {
+                closer.log(
+                    String::from("Peer stopped answering Pings, dropping connection"),
+                    LogLevel::Warning,
+                );
+                let close = CloseFrame {
+                    code: 1001,
+                    reason: String::from("ping timeout"),
+                };
+                _ = closer.send_control(WebSocketOpCode::Close, close.encode());
+                closer.finish(CloseFrame {
+                    code: 1006,
+                    reason: String::from("ping timeout"),
+                });
+            },
+        );
+        self.shared().heartbeat = Some(heartbeat);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Cursor,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    /// Reads from a fixed buffer of incoming bytes and records everything written
+    struct MockStream {
+        incoming: Cursor<Vec<u8>>,
+        outgoing: Vec<u8>,
+        /// Writes fail as if the peer had gone away
+        hung_up: bool,
+    }
+
+    impl Stream for MockStream {
+        fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+            Ok(std::net::SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                4024,
+            ))
+        }
+
+        fn shutdown(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn set_read_timeout(&self, _dur: Option<Duration>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.hung_up {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            self.outgoing.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// An open connection with `frames` waiting to be read
+    fn make_connection(role: Role, frames: Vec<WebSocketFrame>) -> Connection<MockStream> {
+        let incoming = frames.into_iter().flat_map(|f| f.encode()).collect();
+        let mut connection = Connection::new(
+            MockStream {
+                incoming: Cursor::new(incoming),
+                outgoing: Vec::new(),
+                hung_up: false,
+            },
+            role,
+        );
+        connection.open(None, ExtensionChain::default());
+        connection
+    }
+
+    fn sent_frames(connection: &Connection<MockStream>) -> Vec<WebSocketFrame> {
+        let expect_masked = connection.role == Role::Client;
+        let mut decoder = FrameDecoder::new(Some(expect_masked));
+        decoder.allow_rsv(RSV1 | RSV2 | RSV3);
+        decoder.feed(&connection.stream.outgoing).unwrap()
+    }
+
+    fn reverse_chain() -> ExtensionChain {
+        confirm(
+            &[ExtensionOffer::new("x-reverse")],
+            &[Arc::new(crate::extension::tests::Reverse)],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn send_before_open() {
+        let mut connection = Connection::new(
+            MockStream {
+                incoming: Cursor::new(vec![]),
+                outgoing: Vec::new(),
+                hung_up: false,
+            },
+            Role::Client,
+        );
+        assert_eq!(connection.state(), ConnectionState::Connecting);
+        assert_eq!(
+            connection.send_text("too soon").unwrap_err().kind(),
+            std::io::ErrorKind::NotConnected
+        );
+        connection.open(None, ExtensionChain::default());
+        assert_eq!(connection.state(), ConnectionState::Open);
+        assert!(connection.send_text("ok").is_ok());
+    }
+
+    #[test]
+    fn client_masks_server_does_not() {
+        let mut client = make_connection(Role::Client, vec![]);
+        client.send_text("foo").unwrap();
+        let frames = sent_frames(&client);
+        assert!(frames[0].masked);
+        assert_eq!(frames[0].data, b"foo".to_vec());
+
+        let mut server = make_connection(Role::Server, vec![]);
+        server.send_binary(&[1, 2]).unwrap();
+        let frames = sent_frames(&server);
+        assert!(!frames[0].masked);
+        assert_eq!(frames[0].data, vec![1, 2]);
+    }
+
+    #[test]
+    fn seeded_masks() {
+        let masks = || {
+            let mut client = make_connection(Role::Client, vec![]);
+            client.set_random(ChaCha20Rng::from_seed([3; 32]));
+            client.fragment_size = 2;
+            client.send_text("foobar").unwrap();
+            client
+                .send_control(WebSocketOpCode::Ping, Vec::new())
+                .unwrap();
+            sent_frames(&client)
+                .iter()
+                .map(|frame| frame.mask_key.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let first = masks();
+        assert_eq!(first.len(), 4);
+        assert_eq!(masks(), first);
+        // every frame still gets its own key
+        assert!(first.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn we_close_first() {
+        let mut connection = make_connection(
+            Role::Server,
+            vec![WebSocketFrame::new_bin(
+                true,
+                WebSocketOpCode::Close,
+                CloseFrame::new(1000, "bye").unwrap().encode(),
+                Some([1, 2, 3, 4]),
+            )],
+        );
+        connection.close(1000, "bye").unwrap();
+        assert_eq!(connection.state(), ConnectionState::Closing);
+        assert!(connection.close_sent());
+        assert!(!connection.close_received());
+
+        // no more data once we've sent Close
+        assert!(connection.send_text("late").is_err());
+
+        // the peer's answer finishes the handshake, and isn't echoed again
+        assert_eq!(
+            connection.recv().unwrap(),
+            Some(Message::Close(Some(CloseFrame::new(1000, "bye").unwrap())))
+        );
+        assert_eq!(connection.state(), ConnectionState::Closed);
+        assert!(connection.close_received());
+        assert_eq!(connection.recv().unwrap(), None);
+        assert_eq!(sent_frames(&connection).len(), 1);
+        assert_eq!(
+            connection.close_frame(),
+            Some(CloseFrame::new(1000, "bye").unwrap())
+        );
+    }
+
+    #[test]
+    fn peer_closes_first() {
+        let mut connection = make_connection(
+            Role::Client,
+            vec![WebSocketFrame::new_bin(
+                true,
+                WebSocketOpCode::Close,
+                CloseFrame::new(1001, "").unwrap().encode(),
+                None,
+            )],
+        );
+        assert_eq!(
+            connection.recv().unwrap(),
+            Some(Message::Close(Some(CloseFrame::new(1001, "").unwrap())))
+        );
+        assert_eq!(connection.state(), ConnectionState::Closed);
+        assert!(connection.close_sent());
+        assert!(connection.close_received());
+
+        // the Close was echoed with the same status
+        let sent = sent_frames(&connection);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].opcode, WebSocketOpCode::Close);
+        assert_eq!(sent[0].data, vec![0x03, 0xE9]);
+        assert_eq!(connection.close_frame().unwrap().code, 1001);
+    }
+
+    #[test]
+    fn empty_close_reports_no_status() {
+        let mut connection = make_connection(
+            Role::Client,
+            vec![WebSocketFrame::new_bin(
+                true,
+                WebSocketOpCode::Close,
+                vec![],
+                None,
+            )],
+        );
+        assert_eq!(connection.recv().unwrap(), Some(Message::Close(None)));
+        assert_eq!(sent_frames(&connection)[0].data, Vec::<u8>::new());
+        assert_eq!(connection.close_frame().unwrap().code, 1005);
+    }
+
+    #[test]
+    fn dropped_without_close() {
+        let mut connection = make_connection(Role::Client, vec![]);
+        assert_eq!(connection.recv().unwrap(), None);
+        assert_eq!(connection.state(), ConnectionState::Closed);
+        assert!(!connection.close_received());
+        assert_eq!(connection.close_frame().unwrap().code, 1006);
+    }
+
+    #[test]
+    fn ping_is_answered() {
+        let mut connection = make_connection(
+            Role::Client,
+            vec![
+                WebSocketFrame::new_bin(true, WebSocketOpCode::Ping, vec![7, 8], None),
+                WebSocketFrame::new_bin(true, WebSocketOpCode::Text, b"hi".to_vec(), None),
+            ],
+        );
+        assert_eq!(connection.recv().unwrap(), Some(Message::Ping(vec![7, 8])));
+        assert_eq!(
+            connection.recv().unwrap(),
+            Some(Message::Text(String::from("hi")))
+        );
+        let sent = sent_frames(&connection);
+        assert_eq!(sent[0].opcode, WebSocketOpCode::Pong);
+        assert_eq!(sent[0].data, vec![7, 8]);
+    }
+
+    #[test]
+    fn failed_pong_closes_connection() {
+        let mut connection = make_connection(
+            Role::Client,
+            vec![WebSocketFrame::new_bin(
+                true,
+                WebSocketOpCode::Ping,
+                vec![7, 8],
+                None,
+            )],
+        );
+        connection.stream.hung_up = true;
+        assert_eq!(
+            connection.recv().unwrap_err().kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+        assert_eq!(connection.state(), ConnectionState::Closed);
+        assert_eq!(connection.close_frame().unwrap().code, 1006);
+    }
+
+    #[test]
+    fn unread_handshake_bytes_come_first() {
+        let first = WebSocketFrame::new_bin(true, WebSocketOpCode::Text, b"a".to_vec(), None);
+        let second = WebSocketFrame::new_bin(true, WebSocketOpCode::Text, b"b".to_vec(), None);
+        // the handshake read all of the first frame and the start of the second
+        let mut unread = first.encode();
+        let second = second.encode();
+        unread.push(second[0]);
+        let mut connection = make_connection(Role::Client, vec![]);
+        connection.stream.incoming = Cursor::new(second[1..].to_vec());
+        connection.unread(unread);
+
+        assert_eq!(
+            connection.recv().unwrap(),
+            Some(Message::Text(String::from("a")))
+        );
+        assert_eq!(
+            connection.recv().unwrap(),
+            Some(Message::Text(String::from("b")))
+        );
+    }
+
+    #[test]
+    fn extensions_transform_messages() {
+        let mut incoming =
+            WebSocketFrame::new_bin(true, WebSocketOpCode::Text, b"olleh".to_vec(), None);
+        incoming.rsv = RSV2;
+        let mut connection = make_connection(Role::Client, vec![incoming]);
+        connection.open(None, reverse_chain());
+        assert_eq!(connection.extensions(), vec![String::from("x-reverse")]);
+
+        connection.fragment_size = 2;
+        connection.send_text("abc").unwrap();
+        let sent = sent_frames(&connection);
+        // only the first frame of the message carries the bit
+        assert_eq!(sent[0].rsv, RSV2);
+        assert_eq!(sent[1].rsv, 0);
+        assert_eq!(
+            sent.iter().flat_map(|f| f.data.clone()).collect::<Vec<_>>(),
+            b"cba".to_vec()
+        );
+
+        assert_eq!(
+            connection.recv().unwrap(),
+            Some(Message::Text(String::from("hello")))
+        );
+
+        // RSV1 isn't claimed by anything
+        let mut unclaimed =
+            WebSocketFrame::new_bin(true, WebSocketOpCode::Text, b"hi".to_vec(), None);
+        unclaimed.rsv = RSV1;
+        let mut connection = make_connection(Role::Client, vec![unclaimed]);
+        connection.open(None, reverse_chain());
+        assert!(connection.recv().is_err());
+        assert_eq!(connection.state(), ConnectionState::Closed);
+    }
+
+    /// Fails every incoming message with a code and reason that can't go in a Close frame
+    struct Unruly;
+
+    impl Extension for Unruly {
+        fn name(&self) -> &str {
+            "x-unruly"
+        }
+
+        fn accept(&self, _offer: &ExtensionOffer) -> Option<(ExtensionOffer, Box<dyn Extension>)> {
+            Some((ExtensionOffer::new(self.name()), Box::new(Unruly)))
+        }
+
+        fn accepted(&self, _response: &ExtensionOffer) -> Result<Box<dyn Extension>, String> {
+            Ok(Box::new(Unruly))
+        }
+
+        fn outgoing(&mut self, _message: &mut ExtensionMessage) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn incoming(&mut self, _message: &mut ExtensionMessage) -> Result<(), ExtensionError> {
+            Err(ExtensionError {
+                code: 1006,
+                // 200 bytes of two byte characters
+                reason: "é".repeat(100),
+            })
+        }
+    }
+
+    #[test]
+    fn extension_error_fits_close_frame() {
+        let incoming = WebSocketFrame::new_bin(true, WebSocketOpCode::Text, b"hi".to_vec(), None);
+        let mut connection = make_connection(Role::Client, vec![incoming]);
+        let chain = confirm(&[ExtensionOffer::new("x-unruly")], &[Arc::new(Unruly)]).unwrap();
+        connection.open(None, chain);
+        assert!(connection.recv().is_err());
+
+        let sent = sent_frames(&connection);
+        assert_eq!(sent[0].opcode, WebSocketOpCode::Close);
+        assert!(sent[0].data.len() <= 125);
+        let close = CloseFrame::parse(&sent[0].data).unwrap().unwrap();
+        assert_eq!(close.code, 1011);
+        assert_eq!(close.reason, "é".repeat(61));
+    }
+
+    #[test]
+    fn protocol_error_fails_connection() {
+        // servers only accept masked frames
+        let mut connection = make_connection(
+            Role::Server,
+            vec![WebSocketFrame::new_bin(
+                true,
+                WebSocketOpCode::Text,
+                b"hi".to_vec(),
+                None,
+            )],
+        );
+        assert!(connection.recv().is_err());
+        assert_eq!(connection.state(), ConnectionState::Closed);
+        let sent = sent_frames(&connection);
+        assert_eq!(sent[0].opcode, WebSocketOpCode::Close);
+        assert_eq!(sent[0].data[..2], 1002u16.to_be_bytes());
+        assert_eq!(connection.close_frame().unwrap().code, 1006);
+    }
+}
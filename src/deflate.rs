@@ -0,0 +1,961 @@
+use std::{cmp::Reverse, collections::BinaryHeap, fmt};
+
+/// Largest window DEFLATE allows, https://www.rfc-editor.org/rfc/rfc1951#section-2
+const MAX_WINDOW: usize = 1 << 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash are tried when looking for a match
+const MAX_CHAIN: usize = 128;
+/// Matches at least this long are taken straight away instead of checking whether the next
+/// position has a longer one
+const LAZY_LIMIT: usize = 32;
+const HASH_BITS: u32 = 15;
+/// Tokens per block. Each block gets its own Huffman codes, so shorter blocks adapt faster but
+/// spend more on headers.
+const BLOCK_TOKENS: usize = 1 << 14;
+const END_OF_BLOCK: usize = 256;
+
+/// https://www.rfc-editor.org/rfc/rfc1951#section-3.2.5
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order the code length code lengths are sent in, https://www.rfc-editor.org/rfc/rfc1951#section-3.2.7
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Everything that can be wrong with compressed input
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum InflateError {
+    /// Input ended partway through a block
+    Truncated,
+    /// Input isn't valid DEFLATE data
+    Invalid(&'static str),
+    /// Output would be larger than the caller allows
+    TooLarge,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InflateError::Truncated => write!(f, "compressed data is truncated"),
+            InflateError::Invalid(reason) => write!(f, "invalid compressed data, {}", reason),
+            InflateError::TooLarge => write!(f, "decompressed data is too large"),
+        }
+    }
+}
+
+/// The fixed litlen and distance code lengths, https://www.rfc-editor.org/rfc/rfc1951#section-3.2.6
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut litlen = vec![8u8; 288];
+    litlen[144..256].fill(9);
+    litlen[256..280].fill(7);
+    // distance codes 30 and 31 can't occur, but make the code complete
+    (litlen, vec![5u8; 32])
+}
+
+/// Index into LENGTH_BASE for a match length
+fn length_index(len: usize) -> usize {
+    LENGTH_BASE
+        .iter()
+        .rposition(|base| *base as usize <= len)
+        .expect("match length is at least 3")
+}
+
+/// Index into DIST_BASE for a match distance
+fn dist_index(dist: usize) -> usize {
+    DIST_BASE
+        .iter()
+        .rposition(|base| *base as usize <= dist)
+        .expect("match distance is at least 1")
+}
+
+/// Reads bits least significant first, as DEFLATE packs them
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            buf: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, InflateError> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or(InflateError::Truncated)?;
+            self.buf |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.buf & ((1 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Skip to the start of the next byte. Bytes are only loaded as they're needed, so whatever is
+    /// left in the buffer is the rest of the current byte.
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+
+    /// Whether every whole byte has been read
+    fn at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+/// A canonical Huffman code for decoding, stored as the number of codes of each length and the
+/// symbols ordered by code
+struct Huffman {
+    count: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Build the code from each symbol's code length, 0 meaning the symbol isn't used. Incomplete
+    /// codes are only allowed when there's a single code, as encoders emit for a lone distance.
+    fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut count = [0u16; 16];
+        for len in lengths {
+            count[*len as usize] += 1;
+        }
+        count[0] = 0;
+
+        let mut left: i32 = 1;
+        for n in &count[1..] {
+            left = (left << 1) - *n as i32;
+            if left < 0 {
+                return Err(InflateError::Invalid("over-subscribed huffman code"));
+            }
+        }
+        let codes: u16 = count.iter().sum();
+        if left > 0 && codes > 1 {
+            return Err(InflateError::Invalid("incomplete huffman code"));
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + count[len];
+        }
+        let mut symbols = vec![0u16; codes as usize];
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                symbols[offsets[*len as usize] as usize] = symbol as u16;
+                offsets[*len as usize] += 1;
+            }
+        }
+        Ok(Huffman { count, symbols })
+    }
+
+    /// Read one symbol a bit at a time. Canonical codes of each length are consecutive, so this
+    /// only has to track where the current length's codes start.
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.count[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::Invalid("no such huffman code"))
+    }
+}
+
+/// Decompresses raw DEFLATE data, https://www.rfc-editor.org/rfc/rfc1951
+/// With `context_takeover` the last 32KiB of output is kept between calls, so each input can refer
+/// back to what came before it.
+#[derive(Debug, Default)]
+pub(crate) struct Inflater {
+    context_takeover: bool,
+    history: Vec<u8>,
+}
+
+impl Inflater {
+    pub(crate) fn new(context_takeover: bool) -> Inflater {
+        Inflater {
+            context_takeover,
+            history: Vec::new(),
+        }
+    }
+
+    /// Decompress every block in `data`, stopping after a final block or at the end of the input,
+    /// which has to land on a block boundary. Fails with `TooLarge` rather than produce more than
+    /// `max_len` bytes.
+    pub(crate) fn decompress(
+        &mut self,
+        data: &[u8],
+        max_len: usize,
+    ) -> Result<Vec<u8>, InflateError> {
+        // earlier output goes at the front for back-references to reach into
+        let mut out = std::mem::take(&mut self.history);
+        let start = out.len();
+        let limit = start.saturating_add(max_len);
+        let mut reader = BitReader::new(data);
+        let result = loop {
+            if reader.at_end() {
+                break Ok(());
+            }
+            let last = match reader.bits(1) {
+                Ok(bit) => bit == 1,
+                Err(e) => break Err(e),
+            };
+            let block = match reader.bits(2) {
+                Ok(0) => stored(&mut reader, &mut out, limit),
+                Ok(1) => {
+                    let (litlen, dist) = fixed_lengths();
+                    Huffman::new(&litlen).and_then(|litlen| {
+                        Huffman::new(&dist)
+                            .and_then(|dist| codes(&mut reader, &mut out, limit, &litlen, &dist))
+                    })
+                }
+                Ok(2) => dynamic(&mut reader, &mut out, limit),
+                Ok(_) => Err(InflateError::Invalid("reserved block type")),
+                Err(e) => Err(e),
+            };
+            if block.is_err() || last {
+                break block;
+            }
+        };
+        // a failed stream can't be picked back up, and a broken window would only cause confusion
+        // on the next message
+        result?;
+
+        let output = out[start..].to_vec();
+        if self.context_takeover {
+            out.drain(..out.len().saturating_sub(MAX_WINDOW));
+            self.history = out;
+        }
+        Ok(output)
+    }
+}
+
+fn stored(reader: &mut BitReader, out: &mut Vec<u8>, limit: usize) -> Result<(), InflateError> {
+    reader.align();
+    let len = reader.bits(16)? as usize;
+    if reader.bits(16)? as usize != !len & 0xFFFF {
+        return Err(InflateError::Invalid(
+            "stored block length doesn't match its complement",
+        ));
+    }
+    let bytes = reader
+        .data
+        .get(reader.pos..reader.pos + len)
+        .ok_or(InflateError::Truncated)?;
+    if out.len() + len > limit {
+        return Err(InflateError::TooLarge);
+    }
+    out.extend_from_slice(bytes);
+    reader.pos += len;
+    Ok(())
+}
+
+fn codes(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    limit: usize,
+    litlen: &Huffman,
+    dist: &Huffman,
+) -> Result<(), InflateError> {
+    loop {
+        let symbol = litlen.decode(reader)? as usize;
+        if symbol < 256 {
+            if out.len() >= limit {
+                return Err(InflateError::TooLarge);
+            }
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+
+        let index = symbol - 257;
+        if index >= LENGTH_BASE.len() {
+            return Err(InflateError::Invalid("invalid length symbol"));
+        }
+        let len = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+        let index = dist.decode(reader)? as usize;
+        if index >= DIST_BASE.len() {
+            return Err(InflateError::Invalid("invalid distance symbol"));
+        }
+        let distance = DIST_BASE[index] as usize + reader.bits(DIST_EXTRA[index] as u32)? as usize;
+        if distance > out.len() {
+            return Err(InflateError::Invalid("distance is too far back"));
+        }
+        if out.len() + len > limit {
+            return Err(InflateError::TooLarge);
+        }
+        // the match may overlap what it's producing, so this has to go byte by byte
+        let from = out.len() - distance;
+        for i in 0..len {
+            out.push(out[from + i]);
+        }
+    }
+}
+
+fn dynamic(reader: &mut BitReader, out: &mut Vec<u8>, limit: usize) -> Result<(), InflateError> {
+    let nlen = reader.bits(5)? as usize + 257;
+    let ndist = reader.bits(5)? as usize + 1;
+    let ncode = reader.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(InflateError::Invalid("too many length or distance codes"));
+    }
+
+    let mut lengths = [0u8; 19];
+    for index in CODE_LENGTH_ORDER.iter().take(ncode) {
+        lengths[*index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&lengths)?;
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let symbol = code_lengths.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => match i {
+                0 => return Err(InflateError::Invalid("repeat with no previous length")),
+                _ => (lengths[i - 1], 3 + reader.bits(2)? as usize),
+            },
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if i + repeat > nlen + ndist {
+            return Err(InflateError::Invalid("code lengths overrun"));
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    if lengths[END_OF_BLOCK] == 0 {
+        return Err(InflateError::Invalid("no end of block code"));
+    }
+
+    let litlen = Huffman::new(&lengths[..nlen])?;
+    let dist = Huffman::new(&lengths[nlen..])?;
+    codes(reader, out, limit, &litlen, &dist)
+}
+
+/// Writes bits least significant first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buf: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, n: u32) {
+        self.buf |= (value as u64) << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.bytes.push(self.buf as u8);
+            self.buf >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are defined most significant bit first, so they go out reversed
+    fn code(&mut self, code: u16, len: u8) {
+        let reversed = code.reverse_bits() >> (16 - len as u32);
+        self.bits(reversed as u32, len as u32);
+    }
+
+    /// Pad with zeros out to a byte boundary
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.bits(0, 8 - self.count);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Literal(u8),
+    Match { len: usize, dist: usize },
+}
+
+/// Huffman code lengths for symbols with the given frequencies, none longer than `limit`. When the
+/// optimal code is too deep the frequencies are flattened until it fits. At least two symbols
+/// always get a code, so the code is complete and any decoder will take it.
+fn code_lengths(freqs: &[u32], limit: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    for i in 0..freqs.len() {
+        if freqs.iter().filter(|f| **f > 0).count() >= 2 {
+            break;
+        }
+        freqs[i] = freqs[i].max(1);
+    }
+
+    loop {
+        // leaves first, then internal nodes, each pointing at its parent
+        let mut parents: Vec<usize> = vec![usize::MAX; freqs.len()];
+        let mut heap: BinaryHeap<Reverse<(u64, usize)>> = freqs
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| **f > 0)
+            .map(|(symbol, f)| Reverse((*f as u64, symbol)))
+            .collect();
+        while heap.len() > 1 {
+            let Reverse((a, left)) = heap.pop().expect("two nodes left");
+            let Reverse((b, right)) = heap.pop().expect("two nodes left");
+            let node = parents.len();
+            parents.push(usize::MAX);
+            parents[left] = node;
+            parents[right] = node;
+            heap.push(Reverse((a + b, node)));
+        }
+
+        let lengths: Vec<u8> = (0..freqs.len())
+            .map(|symbol| {
+                if freqs[symbol] == 0 {
+                    return 0;
+                }
+                let mut depth = 0u32;
+                let mut node = symbol;
+                while parents[node] != usize::MAX {
+                    node = parents[node];
+                    depth += 1;
+                }
+                depth.min(u8::MAX as u32) as u8
+            })
+            .collect();
+        if lengths.iter().all(|len| *len <= limit) {
+            return lengths;
+        }
+        for f in freqs.iter_mut().filter(|f| **f > 0) {
+            *f = (*f >> 1).max(1);
+        }
+    }
+}
+
+/// Canonical codes for the given code lengths, https://www.rfc-editor.org/rfc/rfc1951#section-3.2.2
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut count = [0u16; 16];
+    for len in lengths {
+        count[*len as usize] += 1;
+    }
+    count[0] = 0;
+    let mut next = [0u16; 16];
+    for len in 1..16 {
+        next[len] = (next[len - 1] + count[len - 1]) << 1;
+    }
+    lengths
+        .iter()
+        .map(|len| {
+            if *len == 0 {
+                return 0;
+            }
+            let code = next[*len as usize];
+            next[*len as usize] += 1;
+            code
+        })
+        .collect()
+}
+
+/// Run length encode the code lengths of a dynamic block with symbols 16-18, as
+/// (symbol, extra bits value, extra bit count)
+fn encode_code_lengths(lengths: &[u8]) -> Vec<(usize, u32, u32)> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let value = lengths[i];
+        let mut run = lengths[i..].iter().take_while(|len| **len == value).count();
+        i += run;
+        if value == 0 {
+            while run >= 11 {
+                let n = run.min(138);
+                encoded.push((18, (n - 11) as u32, 7));
+                run -= n;
+            }
+            if run >= 3 {
+                encoded.push((17, (run - 3) as u32, 3));
+                run = 0;
+            }
+        } else {
+            encoded.push((value as usize, 0, 0));
+            run -= 1;
+            while run >= 3 {
+                let n = run.min(6);
+                encoded.push((16, (n - 3) as u32, 2));
+                run -= n;
+            }
+        }
+        encoded.extend(std::iter::repeat_n((value as usize, 0, 0), run));
+    }
+    encoded
+}
+
+/// Compresses into raw DEFLATE data, https://www.rfc-editor.org/rfc/rfc1951
+/// Matches never reach back further than `1 << window_bits` bytes. With `context_takeover` they
+/// can reach into the data from earlier calls too.
+#[derive(Debug)]
+pub(crate) struct Deflater {
+    window: usize,
+    context_takeover: bool,
+    history: Vec<u8>,
+}
+
+impl Deflater {
+    pub(crate) fn new(window_bits: u8, context_takeover: bool) -> Deflater {
+        Deflater {
+            window: (1 << window_bits).min(MAX_WINDOW),
+            context_takeover,
+            history: Vec::new(),
+        }
+    }
+
+    /// Compress `data` into non-final blocks followed by an empty stored block, so the output
+    /// ends on a byte boundary with `00 00 FF FF` and more can follow it
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut self.history);
+        let start = buf.len();
+        buf.extend_from_slice(data);
+        let tokens = self.tokenize(&buf, start);
+
+        let mut writer = BitWriter::default();
+        let mut pos = start;
+        for block in tokens.chunks(BLOCK_TOKENS) {
+            let len: usize = block
+                .iter()
+                .map(|token| match token {
+                    Token::Literal(_) => 1,
+                    Token::Match { len, .. } => *len,
+                })
+                .sum();
+            write_block(&mut writer, block, &buf[pos..pos + len]);
+            pos += len;
+        }
+
+        // sync flush: an empty stored block
+        writer.bits(0, 3);
+        writer.align();
+        writer.bytes.extend_from_slice(&[0x00, 0x00, 0xFF, 0xFF]);
+
+        if self.context_takeover {
+            buf.drain(..buf.len().saturating_sub(self.window));
+            self.history = buf;
+        }
+        writer.bytes
+    }
+
+    /// LZ77, https://www.rfc-editor.org/rfc/rfc1951#section-4
+    /// Finds matches with hash chains over every 3 byte sequence in the window, and holds off on a
+    /// short match if the next position has a longer one.
+    fn tokenize(&self, buf: &[u8], start: usize) -> Vec<Token> {
+        let mut head = vec![usize::MAX; 1 << HASH_BITS];
+        // prev only has to cover the window, chains are cut off at the window's edge anyway
+        let mut prev = vec![usize::MAX; MAX_WINDOW];
+        let hash = |i: usize| {
+            let h = (buf[i] as u32) << 16 | (buf[i + 1] as u32) << 8 | buf[i + 2] as u32;
+            (h.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+        };
+        let insert = |i: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>| {
+            if i + MIN_MATCH <= buf.len() {
+                let h = hash(i);
+                prev[i % MAX_WINDOW] = head[h];
+                head[h] = i;
+            }
+        };
+        let longest_match = |i: usize, head: &Vec<usize>, prev: &Vec<usize>| {
+            if i + MIN_MATCH > buf.len() {
+                return None;
+            }
+            let max_len = (buf.len() - i).min(MAX_MATCH);
+            let (mut best_len, mut best_dist) = (MIN_MATCH - 1, 0);
+            let mut candidate = head[hash(i)];
+            for _ in 0..MAX_CHAIN {
+                if candidate == usize::MAX || i - candidate > self.window {
+                    break;
+                }
+                if buf[candidate + best_len] == buf[i + best_len] {
+                    let len = buf[candidate..]
+                        .iter()
+                        .zip(&buf[i..i + max_len])
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    if len > best_len {
+                        (best_len, best_dist) = (len, i - candidate);
+                        if len == max_len {
+                            break;
+                        }
+                    }
+                }
+                candidate = prev[candidate % MAX_WINDOW];
+            }
+            (best_len >= MIN_MATCH).then_some((best_len, best_dist))
+        };
+
+        for i in start.saturating_sub(self.window)..start {
+            insert(i, &mut head, &mut prev);
+        }
+        let mut tokens = Vec::new();
+        let mut i = start;
+        while i < buf.len() {
+            let found = longest_match(i, &head, &prev);
+            insert(i, &mut head, &mut prev);
+            let Some((len, dist)) = found else {
+                tokens.push(Token::Literal(buf[i]));
+                i += 1;
+                continue;
+            };
+            if len < LAZY_LIMIT {
+                if let Some((next_len, _)) = longest_match(i + 1, &head, &prev) {
+                    if next_len > len {
+                        tokens.push(Token::Literal(buf[i]));
+                        i += 1;
+                        continue;
+                    }
+                }
+            }
+            tokens.push(Token::Match { len, dist });
+            for j in i + 1..i + len {
+                insert(j, &mut head, &mut prev);
+            }
+            i += len;
+        }
+        tokens
+    }
+}
+
+/// Write one block of `tokens`, covering the uncompressed `raw` bytes, as whichever of a dynamic,
+/// fixed or stored block comes out smallest
+fn write_block(writer: &mut BitWriter, tokens: &[Token], raw: &[u8]) {
+    let mut litlen_freqs = vec![0u32; 286];
+    let mut dist_freqs = vec![0u32; 30];
+    for token in tokens {
+        match token {
+            Token::Literal(b) => litlen_freqs[*b as usize] += 1,
+            Token::Match { len, dist } => {
+                litlen_freqs[257 + length_index(*len)] += 1;
+                dist_freqs[dist_index(*dist)] += 1;
+            }
+        }
+    }
+    litlen_freqs[END_OF_BLOCK] += 1;
+
+    // cost in bits of the tokens with the given code lengths, not counting any header
+    let token_bits = |litlen: &[u8], dist: &[u8]| -> u64 {
+        let mut bits = litlen[END_OF_BLOCK] as u64;
+        for token in tokens {
+            bits += match token {
+                Token::Literal(b) => litlen[*b as usize] as u64,
+                Token::Match { len, dist: d } => {
+                    let (l, d) = (length_index(*len), dist_index(*d));
+                    (litlen[257 + l] + LENGTH_EXTRA[l] + dist[d] + DIST_EXTRA[d]) as u64
+                }
+            }
+        }
+        bits
+    };
+
+    let litlen = code_lengths(&litlen_freqs, 15);
+    let dist = code_lengths(&dist_freqs, 15);
+    let nlen = 257.max(litlen.iter().rposition(|len| *len != 0).unwrap_or(0) + 1);
+    let ndist = 1.max(dist.iter().rposition(|len| *len != 0).unwrap_or(0) + 1);
+    let mut all_lengths = litlen[..nlen].to_vec();
+    all_lengths.extend_from_slice(&dist[..ndist]);
+    let encoded = encode_code_lengths(&all_lengths);
+    let mut code_length_freqs = vec![0u32; 19];
+    for (symbol, _, _) in &encoded {
+        code_length_freqs[*symbol] += 1;
+    }
+    let code_length_lengths = code_lengths(&code_length_freqs, 7);
+    let ncode = 4.max(
+        CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|i| code_length_lengths[*i] != 0)
+            .unwrap_or(0)
+            + 1,
+    );
+    let dynamic_bits = 3
+        + 14
+        + 3 * ncode as u64
+        + encoded
+            .iter()
+            .map(|(symbol, _, extra)| code_length_lengths[*symbol] as u64 + *extra as u64)
+            .sum::<u64>()
+        + token_bits(&litlen, &dist);
+
+    let (fixed_litlen, fixed_dist) = fixed_lengths();
+    let fixed_bits = 3 + token_bits(&fixed_litlen, &fixed_dist);
+    // header, padding, and LEN and NLEN for every 64KiB
+    let stored_bits = (raw.len().div_ceil(0xFFFF).max(1) * 5 * 8 + raw.len() * 8) as u64;
+
+    if stored_bits < dynamic_bits.min(fixed_bits) {
+        let mut chunks = raw.chunks(0xFFFF).peekable();
+        if chunks.peek().is_none() {
+            return;
+        }
+        for chunk in chunks {
+            writer.bits(0b00 << 1, 3);
+            writer.align();
+            let len = chunk.len() as u32;
+            writer.bits(len, 16);
+            writer.bits(!len & 0xFFFF, 16);
+            writer.bytes.extend_from_slice(chunk);
+        }
+    } else if fixed_bits <= dynamic_bits {
+        writer.bits(0b01 << 1, 3);
+        write_tokens(writer, tokens, &fixed_litlen, &fixed_dist);
+    } else {
+        writer.bits(0b10 << 1, 3);
+        writer.bits((nlen - 257) as u32, 5);
+        writer.bits((ndist - 1) as u32, 5);
+        writer.bits((ncode - 4) as u32, 4);
+        for i in CODE_LENGTH_ORDER.iter().take(ncode) {
+            writer.bits(code_length_lengths[*i] as u32, 3);
+        }
+        let codes = canonical_codes(&code_length_lengths);
+        for (symbol, extra, extra_len) in encoded {
+            writer.code(codes[symbol], code_length_lengths[symbol]);
+            writer.bits(extra, extra_len);
+        }
+        write_tokens(writer, tokens, &litlen, &dist);
+    }
+}
+
+fn write_tokens(writer: &mut BitWriter, tokens: &[Token], litlen: &[u8], dist: &[u8]) {
+    let litlen_codes = canonical_codes(litlen);
+    let dist_codes = canonical_codes(dist);
+    for token in tokens {
+        match token {
+            Token::Literal(b) => writer.code(litlen_codes[*b as usize], litlen[*b as usize]),
+            Token::Match { len, dist: d } => {
+                let l = length_index(*len);
+                writer.code(litlen_codes[257 + l], litlen[257 + l]);
+                writer.bits(
+                    (*len - LENGTH_BASE[l] as usize) as u32,
+                    LENGTH_EXTRA[l] as u32,
+                );
+                let d_index = dist_index(*d);
+                writer.code(dist_codes[d_index], dist[d_index]);
+                writer.bits(
+                    (*d - DIST_BASE[d_index] as usize) as u32,
+                    DIST_EXTRA[d_index] as u32,
+                );
+            }
+        }
+    }
+    writer.code(litlen_codes[END_OF_BLOCK], litlen[END_OF_BLOCK]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic bytes that don't compress
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u32 = 0x1234_5678;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn json(len: usize) -> Vec<u8> {
+        let mut json = String::from("[");
+        let mut i = 0;
+        while json.len() < len {
+            json += &format!(
+                "{{\"id\":{},\"name\":\"user{}\",\"active\":{},\"tags\":[\"a\",\"b\"]}},",
+                i,
+                i * 7 % 13,
+                i % 3 == 0
+            );
+            i += 1;
+        }
+        json.into_bytes()
+    }
+
+    fn round_trip(deflater: &mut Deflater, inflater: &mut Inflater, data: &[u8]) -> usize {
+        let compressed = deflater.compress(data);
+        assert!(compressed.ends_with(&[0x00, 0x00, 0xFF, 0xFF]));
+        assert_eq!(inflater.decompress(&compressed, usize::MAX).unwrap(), data);
+        compressed.len()
+    }
+
+    #[test]
+    fn rfc7692_examples() {
+        // https://www.rfc-editor.org/rfc/rfc7692#section-7.2.3
+        let mut inflater = Inflater::new(true);
+        let hello = [
+            0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x00, 0x00, 0xff, 0xff,
+        ];
+        assert_eq!(inflater.decompress(&hello, 100).unwrap(), b"Hello");
+        // the same message again, now referring back to the first one
+        let again = [0xf2, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff];
+        assert_eq!(inflater.decompress(&again, 100).unwrap(), b"Hello");
+
+        let stored = [
+            0x00, 0x05, 0x00, 0xfa, 0xff, b'H', b'e', b'l', b'l', b'o', 0x00,
+        ];
+        let mut inflater = Inflater::new(false);
+        assert_eq!(
+            inflater
+                .decompress(&[&stored[..], &[0x00, 0x00, 0xff, 0xff]].concat(), 100)
+                .unwrap(),
+            b"Hello"
+        );
+
+        // a final block ends the data, whatever follows it
+        let last = [
+            0xf3, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x00, 0x00, 0xff, 0xff,
+        ];
+        assert_eq!(inflater.decompress(&last, 100).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut deflater = Deflater::new(15, false);
+        let mut inflater = Inflater::new(false);
+        round_trip(&mut deflater, &mut inflater, b"");
+        round_trip(&mut deflater, &mut inflater, b"a");
+        round_trip(&mut deflater, &mut inflater, b"Hello");
+        round_trip(&mut deflater, &mut inflater, &[0u8; 100_000]);
+        round_trip(&mut deflater, &mut inflater, &noise(200_000));
+
+        let data = json(300_000);
+        let len = round_trip(&mut deflater, &mut inflater, &data);
+        assert!(len < data.len() / 5, "{} of {}", len, data.len());
+    }
+
+    #[test]
+    fn incompressible_data_is_stored() {
+        let data = noise(1000);
+        let compressed = Deflater::new(15, false).compress(&data);
+        assert!(compressed.len() <= data.len() + 10);
+    }
+
+    #[test]
+    fn context_takeover() {
+        let data = json(2000);
+        let mut deflater = Deflater::new(15, true);
+        let mut inflater = Inflater::new(true);
+        let first = round_trip(&mut deflater, &mut inflater, &data);
+        let second = round_trip(&mut deflater, &mut inflater, &data);
+        assert!(second < first / 5, "{} then {}", first, second);
+
+        // without it each message stands alone
+        let mut deflater = Deflater::new(15, false);
+        let first = deflater.compress(&data);
+        assert_eq!(deflater.compress(&data), first);
+    }
+
+    #[test]
+    fn window_bits() {
+        let mut data = noise(300);
+        data.extend_from_slice(&noise(300));
+        for bits in [8, 9] {
+            let deflater = Deflater::new(bits, false);
+            for token in deflater.tokenize(&data, 0) {
+                if let Token::Match { dist, .. } = token {
+                    assert!(dist <= 1 << bits, "{} with {} bits", dist, bits);
+                }
+            }
+        }
+        // the repeat is 300 bytes back, only reachable with a bigger window
+        let small = Deflater::new(8, false).compress(&data).len();
+        let big = Deflater::new(9, false).compress(&data).len();
+        assert!(big + 200 < small, "{} vs {}", big, small);
+
+        let mut deflater = Deflater::new(8, true);
+        let mut inflater = Inflater::new(true);
+        for _ in 0..3 {
+            round_trip(&mut deflater, &mut inflater, &json(5000));
+        }
+    }
+
+    #[test]
+    fn limits_output() {
+        let compressed = Deflater::new(15, false).compress(&[b'a'; 10_000]);
+        assert_eq!(
+            Inflater::new(false).decompress(&compressed, 9_999),
+            Err(InflateError::TooLarge)
+        );
+        assert_eq!(
+            Inflater::new(false)
+                .decompress(&compressed, 10_000)
+                .unwrap()
+                .len(),
+            10_000
+        );
+    }
+
+    #[test]
+    fn invalid_data() {
+        let mut inflater = Inflater::new(false);
+        // reserved block type
+        assert!(matches!(
+            inflater.decompress(&[0x07], 100),
+            Err(InflateError::Invalid(_))
+        ));
+        // stored length not matching its complement
+        assert!(matches!(
+            inflater.decompress(&[0x00, 0x05, 0x00, 0x00, 0x00], 100),
+            Err(InflateError::Invalid(_))
+        ));
+        // fixed block cut off partway
+        assert_eq!(
+            inflater.decompress(&[0xf2, 0x48], 100),
+            Err(InflateError::Truncated)
+        );
+        // back-reference before the start of the data
+        let mut writer = BitWriter::default();
+        writer.bits(0b011, 3);
+        write_tokens(
+            &mut writer,
+            &[Token::Match { len: 3, dist: 1 }],
+            &fixed_lengths().0,
+            &fixed_lengths().1,
+        );
+        writer.align();
+        assert_eq!(
+            inflater.decompress(&writer.bytes, 100),
+            Err(InflateError::Invalid("distance is too far back"))
+        );
+    }
+
+    #[test]
+    fn huffman_lengths() {
+        let lengths = code_lengths(&[1, 1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024], 7);
+        assert!(lengths.iter().all(|len| (1..=7).contains(len)));
+        // Kraft: a complete code sums to exactly 1
+        let kraft: f64 = lengths.iter().map(|len| 0.5f64.powi(*len as i32)).sum();
+        assert_eq!(kraft, 1.0);
+
+        // lone symbols still get a complete code
+        let lengths = code_lengths(&[0, 0, 5, 0], 15);
+        assert_eq!(lengths.iter().filter(|len| **len == 1).count(), 2);
+        assert_eq!(code_lengths(&[0, 0, 0], 15), vec![1, 1, 0]);
+    }
+}
@@ -0,0 +1,505 @@
+use crate::http::is_tchar;
+use std::{fmt, sync::Arc};
+
+/// RSV bits as they're passed around, shifted down out of the frame header
+pub const RSV1: u8 = 0b100;
+pub const RSV2: u8 = 0b010;
+pub const RSV3: u8 = 0b001;
+
+/// One entry of a Sec-WebSocket-Extensions header: an extension's name and its parameters. Used
+/// for both the client's offers and the server's responses to them.
+/// https://www.rfc-editor.org/rfc/rfc6455#section-9.1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionOffer {
+    pub name: String,
+    /// Parameter names and their values, if they have one, in the order they were given
+    pub params: Vec<(String, Option<String>)>,
+}
+
+impl ExtensionOffer {
+    pub fn new(name: &str) -> ExtensionOffer {
+        ExtensionOffer {
+            name: name.to_string(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn with_param(mut self, name: &str, value: Option<&str>) -> ExtensionOffer {
+        self.params
+            .push((name.to_string(), value.map(|v| v.to_string())));
+        self
+    }
+
+    /// Whether the parameter called `name` was given, with or without a value
+    pub fn has_param(&self, name: &str) -> bool {
+        self.params.iter().any(|(param, _)| param == name)
+    }
+
+    /// Value of the parameter called `name`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .and_then(|(_, value)| value.as_deref())
+    }
+}
+
+impl fmt::Display for ExtensionOffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (name, value) in &self.params {
+            match value {
+                None => write!(f, "; {}", name)?,
+                Some(value) if !value.is_empty() && value.chars().all(is_tchar) => {
+                    write!(f, "; {}={}", name, value)?
+                }
+                Some(value) => write!(
+                    f,
+                    "; {}=\"{}\"",
+                    name,
+                    value.replace('\\', "\\\\").replace('"', "\\\"")
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a Sec-WebSocket-Extensions header value, e.g.
+/// `permessage-deflate; client_max_window_bits, x-foo; bar="baz"`
+pub(crate) fn parse_extensions(value: &str) -> Result<Vec<ExtensionOffer>, String> {
+    let mut chars = value.chars().peekable();
+    let mut offers = Vec::new();
+
+    let skip_whitespace = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+    };
+    let token = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        let mut token = String::new();
+        while let Some(c) = chars.next_if(|c| is_tchar(*c)) {
+            token.push(c);
+        }
+        token
+    };
+
+    loop {
+        skip_whitespace(&mut chars);
+        // empty list elements are allowed and skipped
+        if chars.next_if_eq(&',').is_some() {
+            continue;
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let name = token(&mut chars);
+        if name.is_empty() {
+            return Err(String::from("Extension is missing its name"));
+        }
+        let mut offer = ExtensionOffer::new(&name);
+        loop {
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                None | Some(',') => break,
+                Some(';') => {}
+                Some(c) => return Err(format!("Unexpected '{}' after extension {}", c, name)),
+            }
+            skip_whitespace(&mut chars);
+            let param = token(&mut chars);
+            if param.is_empty() {
+                return Err(format!("Extension {} has a parameter without a name", name));
+            }
+            skip_whitespace(&mut chars);
+            if chars.next_if_eq(&'=').is_none() {
+                offer.params.push((param, None));
+                continue;
+            }
+            skip_whitespace(&mut chars);
+            let value = if chars.next_if_eq(&'"').is_some() {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => value.push(c),
+                            None => return Err(String::from("Unterminated quoted string")),
+                        },
+                        Some(c) => value.push(c),
+                        None => return Err(String::from("Unterminated quoted string")),
+                    }
+                }
+                value
+            } else {
+                token(&mut chars)
+            };
+            if value.is_empty() {
+                return Err(format!("Extension parameter {} has an empty value", param));
+            }
+            offer.params.push((param, Some(value)));
+        }
+        offers.push(offer);
+    }
+    Ok(offers)
+}
+
+/// A data message's payload on its way through the extensions, before it's split into frames or
+/// after it's been reassembled from them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionMessage {
+    /// Text rather than Binary
+    pub text: bool,
+    /// RSV bits of the message's first frame. Extensions set the bits they claim on the way out,
+    /// and should clear them on the way in once they've undone whatever they stand for.
+    pub rsv: u8,
+    pub data: Vec<u8>,
+}
+
+/// Why an extension couldn't make sense of an incoming message. The connection is failed with
+/// `code` as its Close status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionError {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl fmt::Display for ExtensionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// A WebSocket extension, https://www.rfc-editor.org/rfc/rfc6455#section-9
+///
+/// The value configured on a `WebSocketServer` or `WebSocketClient` only takes part in the
+/// handshake, and hands back a fresh instance for every connection that agrees to use it. Those
+/// instances see every Text and Binary message: outgoing ones in the order the extensions were
+/// negotiated before they're fragmented, incoming ones in reverse order once they've been
+/// reassembled. Control frames are never passed through extensions.
+pub trait Extension: Send + Sync {
+    /// Extension token used in Sec-WebSocket-Extensions
+    fn name(&self) -> &str;
+
+    /// RSV bits this extension gives a meaning to, out of `RSV1 | RSV2 | RSV3`. Frames from the
+    /// peer with any other bit set fail the connection.
+    fn rsv_bits(&self) -> u8 {
+        0
+    }
+
+    /// Client side, what to offer the server
+    fn offer(&self) -> ExtensionOffer {
+        ExtensionOffer::new(self.name())
+    }
+
+    /// Server side, answer one of the client's offers with the parameters for the response and
+    /// the instance to use for the connection, or decline it with `None`
+    fn accept(&self, offer: &ExtensionOffer) -> Option<(ExtensionOffer, Box<dyn Extension>)>;
+
+    /// Client side, the server accepted the offer with `response`. Returns the instance to use for
+    /// the connection, or a reason to fail the handshake if the response isn't acceptable.
+    fn accepted(&self, response: &ExtensionOffer) -> Result<Box<dyn Extension>, String>;
+
+    /// Transform a message before it's sent
+    fn outgoing(&mut self, _message: &mut ExtensionMessage) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Transform a message after it's received
+    fn incoming(&mut self, _message: &mut ExtensionMessage) -> Result<(), ExtensionError> {
+        Ok(())
+    }
+}
+
+/// The extensions in use on one connection, in the order they were negotiated
+#[derive(Default)]
+pub(crate) struct ExtensionChain {
+    extensions: Vec<Box<dyn Extension>>,
+}
+
+impl fmt::Debug for ExtensionChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// Chains are the same if the same extensions were negotiated, whatever state they've built up
+impl PartialEq for ExtensionChain {
+    fn eq(&self, other: &Self) -> bool {
+        self.names() == other.names()
+    }
+}
+
+impl ExtensionChain {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.extensions
+            .iter()
+            .map(|extension| extension.name().to_string())
+            .collect()
+    }
+
+    /// Every RSV bit claimed by an extension in the chain
+    pub(crate) fn rsv_bits(&self) -> u8 {
+        self.extensions
+            .iter()
+            .fold(0, |bits, extension| bits | extension.rsv_bits())
+    }
+
+    fn push(&mut self, extension: Box<dyn Extension>) -> Result<(), String> {
+        if self.rsv_bits() & extension.rsv_bits() != 0 {
+            return Err(format!(
+                "Extension {} uses RSV bits already claimed by another extension",
+                extension.name()
+            ));
+        }
+        self.extensions.push(extension);
+        Ok(())
+    }
+
+    pub(crate) fn outgoing(&mut self, message: &mut ExtensionMessage) -> std::io::Result<()> {
+        self.extensions
+            .iter_mut()
+            .try_for_each(|extension| extension.outgoing(message))
+    }
+
+    pub(crate) fn incoming(
+        &mut self,
+        message: &mut ExtensionMessage,
+    ) -> Result<(), ExtensionError> {
+        self.extensions
+            .iter_mut()
+            .rev()
+            .try_for_each(|extension| extension.incoming(message))
+    }
+}
+
+/// Server side negotiation. Every offer, in the client's order of preference, goes to the
+/// supported extension with the same name until one accepts. Only one offer per extension is
+/// accepted, and none whose RSV bits clash with an extension already accepted. Returns the
+/// responses for the 101 and the chain for the connection.
+pub(crate) fn negotiate(
+    offers: &[ExtensionOffer],
+    supported: &[Arc<dyn Extension>],
+) -> (Vec<ExtensionOffer>, ExtensionChain) {
+    let mut responses: Vec<ExtensionOffer> = Vec::new();
+    let mut chain = ExtensionChain::default();
+    for offer in offers {
+        if responses.iter().any(|response| response.name == offer.name) {
+            continue;
+        }
+        let Some(extension) = supported.iter().find(|e| e.name() == offer.name) else {
+            continue;
+        };
+        if let Some((response, instance)) = extension.accept(offer) {
+            if chain.push(instance).is_ok() {
+                responses.push(response);
+            }
+        }
+    }
+    (responses, chain)
+}
+
+/// Client side negotiation, checking the server only accepted extensions that were `offered`,
+/// each at most once
+pub(crate) fn confirm(
+    responses: &[ExtensionOffer],
+    offered: &[Arc<dyn Extension>],
+) -> Result<ExtensionChain, String> {
+    let mut chain = ExtensionChain::default();
+    for (i, response) in responses.iter().enumerate() {
+        if responses[..i].iter().any(|r| r.name == response.name) {
+            return Err(format!("Server accepted extension {} twice", response.name));
+        }
+        let extension = offered
+            .iter()
+            .find(|e| e.name() == response.name)
+            .ok_or_else(|| {
+                format!(
+                    "Server accepted extension {} which was not offered",
+                    response.name
+                )
+            })?;
+        chain.push(extension.accepted(response)?)?;
+    }
+    Ok(chain)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Reverses every message's payload and flags it with RSV2
+    pub(crate) struct Reverse;
+
+    impl Extension for Reverse {
+        fn name(&self) -> &str {
+            "x-reverse"
+        }
+
+        fn rsv_bits(&self) -> u8 {
+            RSV2
+        }
+
+        fn accept(&self, offer: &ExtensionOffer) -> Option<(ExtensionOffer, Box<dyn Extension>)> {
+            if offer.has_param("refuse") {
+                return None;
+            }
+            Some((ExtensionOffer::new(self.name()), Box::new(Reverse)))
+        }
+
+        fn accepted(&self, response: &ExtensionOffer) -> Result<Box<dyn Extension>, String> {
+            match response.params.is_empty() {
+                true => Ok(Box::new(Reverse)),
+                false => Err(String::from("x-reverse takes no parameters")),
+            }
+        }
+
+        fn outgoing(&mut self, message: &mut ExtensionMessage) -> std::io::Result<()> {
+            message.data.reverse();
+            message.rsv |= RSV2;
+            Ok(())
+        }
+
+        fn incoming(&mut self, message: &mut ExtensionMessage) -> Result<(), ExtensionError> {
+            if message.rsv & RSV2 == 0 {
+                return Err(ExtensionError {
+                    code: 1002,
+                    reason: String::from("message isn't reversed"),
+                });
+            }
+            message.data.reverse();
+            message.rsv &= !RSV2;
+            Ok(())
+        }
+    }
+
+    /// Claims the same bit as `Reverse`
+    struct Clash;
+
+    impl Extension for Clash {
+        fn name(&self) -> &str {
+            "x-clash"
+        }
+
+        fn rsv_bits(&self) -> u8 {
+            RSV2
+        }
+
+        fn accept(&self, _offer: &ExtensionOffer) -> Option<(ExtensionOffer, Box<dyn Extension>)> {
+            Some((ExtensionOffer::new(self.name()), Box::new(Clash)))
+        }
+
+        fn accepted(&self, _response: &ExtensionOffer) -> Result<Box<dyn Extension>, String> {
+            Ok(Box::new(Clash))
+        }
+    }
+
+    #[test]
+    fn parse_header() {
+        assert_eq!(
+            parse_extensions(
+                "permessage-deflate; client_max_window_bits, x-foo ;bar = \"b\\\"a,z\" ; qux=1,"
+            ),
+            Ok(vec![
+                ExtensionOffer::new("permessage-deflate")
+                    .with_param("client_max_window_bits", None),
+                ExtensionOffer::new("x-foo")
+                    .with_param("bar", Some("b\"a,z"))
+                    .with_param("qux", Some("1")),
+            ])
+        );
+        assert_eq!(parse_extensions(""), Ok(vec![]));
+        assert_eq!(parse_extensions(" , ,"), Ok(vec![]));
+
+        for bad in [
+            "; foo",
+            "foo;",
+            "foo; =1",
+            "foo; a=",
+            "foo; a=\"1",
+            "foo bar",
+            "foo; a=[1]",
+        ] {
+            assert!(parse_extensions(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn format_header() {
+        let offer = ExtensionOffer::new("x-foo")
+            .with_param("a", None)
+            .with_param("b", Some("15"))
+            .with_param("c", Some("two words"));
+        assert_eq!(offer.to_string(), "x-foo; a; b=15; c=\"two words\"");
+        assert_eq!(
+            parse_extensions(&offer.to_string()),
+            Ok(vec![offer.clone()])
+        );
+        assert!(offer.has_param("a"));
+        assert_eq!(offer.param("a"), None);
+        assert_eq!(offer.param("b"), Some("15"));
+        assert!(!offer.has_param("d"));
+    }
+
+    #[test]
+    fn server_negotiation() {
+        let supported: Vec<Arc<dyn Extension>> = vec![Arc::new(Reverse), Arc::new(Clash)];
+        let offers =
+            parse_extensions("x-unknown, x-reverse; refuse, x-reverse, x-reverse, x-clash")
+                .unwrap();
+        let (responses, chain) = negotiate(&offers, &supported);
+        // the refused offer falls back to the next, and x-clash can't share RSV2
+        assert_eq!(responses, vec![ExtensionOffer::new("x-reverse")]);
+        assert_eq!(chain.names(), vec![String::from("x-reverse")]);
+        assert_eq!(chain.rsv_bits(), RSV2);
+
+        let (responses, chain) = negotiate(&offers, &[]);
+        assert!(responses.is_empty());
+        assert!(chain.is_empty());
+    }
+
+    #[test]
+    fn client_confirmation() {
+        let offered: Vec<Arc<dyn Extension>> = vec![Arc::new(Reverse)];
+        let chain = confirm(&parse_extensions("x-reverse").unwrap(), &offered).unwrap();
+        assert_eq!(chain.names(), vec![String::from("x-reverse")]);
+        assert!(confirm(&[], &offered).unwrap().is_empty());
+
+        assert_eq!(
+            confirm(&parse_extensions("x-other").unwrap(), &offered).err(),
+            Some(String::from(
+                "Server accepted extension x-other which was not offered"
+            ))
+        );
+        assert_eq!(
+            confirm(&parse_extensions("x-reverse, x-reverse").unwrap(), &offered).err(),
+            Some(String::from("Server accepted extension x-reverse twice"))
+        );
+        assert_eq!(
+            confirm(&parse_extensions("x-reverse; a=1").unwrap(), &offered).err(),
+            Some(String::from("x-reverse takes no parameters"))
+        );
+    }
+
+    #[test]
+    fn chain_order() {
+        let mut chain = ExtensionChain::default();
+        chain.push(Box::new(Reverse)).unwrap();
+        assert!(chain.push(Box::new(Clash)).is_err());
+
+        let mut message = ExtensionMessage {
+            text: true,
+            rsv: 0,
+            data: b"abc".to_vec(),
+        };
+        chain.outgoing(&mut message).unwrap();
+        assert_eq!(message.data, b"cba".to_vec());
+        assert_eq!(message.rsv, RSV2);
+        chain.incoming(&mut message).unwrap();
+        assert_eq!(message.data, b"abc".to_vec());
+        assert_eq!(message.rsv, 0);
+        assert_eq!(chain.incoming(&mut message).unwrap_err().code, 1002);
+    }
+}
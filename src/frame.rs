@@ -1,47 +1,206 @@
-use std::{collections::VecDeque, io::Read};
+use crate::extension::*;
+use crate::random::*;
+use std::fmt;
+
+/// Largest payload rhubarb will buffer for a single frame before failing the connection with 1009
+const MAX_PAYLOAD_LEN: u64 = 16 * 1024 * 1024;
 
 #[derive(Debug)]
-struct WebSocketFrame {
-    fin: bool,
-    masked: bool,
-    opcode: WebSocketOpCode,
-    payload_len: u64,
-    mask_key: Option<[u8; 4]>,
-    data: Vec<u8>,
+pub(crate) struct WebSocketFrame {
+    pub(crate) fin: bool,
+    /// RSV1-3, shifted down so RSV1 is 0b100. Only extensions give these a meaning.
+    pub(crate) rsv: u8,
+    pub(crate) masked: bool,
+    pub(crate) opcode: WebSocketOpCode,
+    pub(crate) payload_len: u64,
+    pub(crate) mask_key: Option<[u8; 4]>,
+    pub(crate) data: Vec<u8>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum WebSocketOpCode {
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum WebSocketOpCode {
     Continuation,
     Text,
     Binary,
     Close,
     Ping,
     Pong,
-    Reserved,
+}
+
+impl WebSocketOpCode {
+    /// Control frames are the ones with the most significant opcode bit set
+    /// https://www.rfc-editor.org/rfc/rfc6455#section-5.5
+    pub(crate) fn is_control(&self) -> bool {
+        matches!(
+            self,
+            WebSocketOpCode::Close | WebSocketOpCode::Ping | WebSocketOpCode::Pong
+        )
+    }
+}
+
+/// Everything that can be wrong with a frame on the wire. Any of these means the connection has to
+/// be failed, see https://www.rfc-editor.org/rfc/rfc6455#section-7.1.7
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum FrameError {
+    /// Ran out of bytes before the end of the header
+    TruncatedHeader,
+    /// Ran out of bytes before the end of the payload
+    TruncatedPayload,
+    /// Opcode 0x3-0x7 or 0xB-0xF
+    ReservedOpCode(u8),
+    /// RSV1-3 set without an extension that defines them
+    ReservedBits(u8),
+    /// Control frame with a payload over 125 bytes
+    OversizedControlFrame,
+    /// Control frame without FIN set
+    FragmentedControlFrame,
+    /// Length encoded with more bytes than needed
+    NonMinimalLength(u64),
+    /// Length larger than rhubarb is willing to buffer
+    PayloadTooLarge(u64),
+    /// Mask bit doesn't match what this side of the connection requires
+    BadMask,
+    /// Continuation frame with no fragmented message to continue
+    UnexpectedContinuation,
+    /// New Text or Binary frame while a fragmented message is still open
+    ExpectedContinuation,
+    /// Reassembled message larger than rhubarb is willing to buffer
+    MessageTooLarge(usize),
+    /// Text message or close reason that isn't valid UTF-8
+    InvalidUtf8,
+    /// Close frame with a one byte payload, too short for a status code
+    InvalidClosePayload,
+    /// Close frame with a status code that isn't allowed on the wire
+    InvalidCloseCode(u16),
+    /// A negotiated extension couldn't process a message
+    Extension(ExtensionError),
+}
+
+impl FrameError {
+    /// Status code to send in the Close frame when failing the connection because of this error
+    /// https://www.rfc-editor.org/rfc/rfc6455#section-7.4.1
+    pub(crate) fn close_code(&self) -> u16 {
+        match self {
+            FrameError::PayloadTooLarge(_) | FrameError::MessageTooLarge(_) => 1009,
+            FrameError::InvalidUtf8 => 1007,
+            FrameError::Extension(e) => e.code,
+            _ => 1002,
+        }
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TruncatedHeader => write!(f, "frame header is truncated"),
+            FrameError::TruncatedPayload => write!(f, "frame payload is truncated"),
+            FrameError::ReservedOpCode(op) => write!(f, "reserved opcode {:#x}", op),
+            FrameError::ReservedBits(rsv) => write!(f, "reserved bits {:#05b} are set", rsv),
+            FrameError::OversizedControlFrame => {
+                write!(f, "control frame payload is over 125 bytes")
+            }
+            FrameError::FragmentedControlFrame => write!(f, "control frame is fragmented"),
+            FrameError::NonMinimalLength(len) => {
+                write!(f, "payload length {} is not minimally encoded", len)
+            }
+            FrameError::PayloadTooLarge(len) => write!(f, "payload length {} is too large", len),
+            FrameError::BadMask => write!(f, "frame masking is invalid for this endpoint"),
+            FrameError::UnexpectedContinuation => {
+                write!(f, "continuation frame without a message to continue")
+            }
+            FrameError::ExpectedContinuation => {
+                write!(f, "new data frame before the previous message finished")
+            }
+            FrameError::MessageTooLarge(len) => write!(f, "message length {} is too large", len),
+            FrameError::InvalidUtf8 => write!(f, "text is not valid utf8"),
+            FrameError::InvalidClosePayload => write!(f, "close payload is too short"),
+            FrameError::InvalidCloseCode(code) => write!(f, "invalid close code {}", code),
+            FrameError::Extension(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Incrementally decodes frames out of a byte stream. TCP makes no promises about how the bytes of
+/// a frame arrive, so a single read may hold half a header, several frames back to back, or both.
+#[derive(Debug, Default)]
+pub(crate) struct FrameDecoder {
+    buf: Vec<u8>,
+    /// If set, every frame's mask bit must match this
+    expect_masked: Option<bool>,
+    /// RSV bits claimed by negotiated extensions, any others have to be 0
+    allowed_rsv: u8,
+}
+
+impl FrameDecoder {
+    pub(crate) fn new(expect_masked: Option<bool>) -> FrameDecoder {
+        FrameDecoder {
+            buf: Vec::new(),
+            expect_masked,
+            allowed_rsv: 0,
+        }
+    }
+
+    /// Let frames through with any of the `rsv` bits set
+    pub(crate) fn allow_rsv(&mut self, rsv: u8) {
+        self.allowed_rsv = rsv;
+    }
+
+    /// Buffer `bytes` and return every frame that is now complete, in the order they arrived. A
+    /// trailing partial frame is held on to until a later call supplies the rest of it.
+    ///
+    /// Once this returns an error the stream can't be resynchronized and the connection must be
+    /// failed.
+    pub(crate) fn feed(&mut self, bytes: &[u8]) -> Result<Vec<WebSocketFrame>, FrameError> {
+        self.buf.extend_from_slice(bytes);
+        let mut frames = Vec::new();
+        while let Some(len) = WebSocketFrame::frame_len(&self.buf)? {
+            let raw: Vec<u8> = self.buf.drain(..len).collect();
+            let frame = WebSocketFrame::parse(raw)?;
+            if frame.rsv & !self.allowed_rsv != 0 {
+                return Err(FrameError::ReservedBits(frame.rsv & !self.allowed_rsv));
+            }
+            if self
+                .expect_masked
+                .is_some_and(|masked| masked != frame.masked)
+            {
+                return Err(FrameError::BadMask);
+            }
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+}
+
+/// XOR `data` with the masking key, https://www.rfc-editor.org/rfc/rfc6455#section-5.3
+/// Masking and unmasking are the same operation.
+fn apply_mask(data: &mut [u8], key: [u8; 4]) {
+    data.iter_mut()
+        .zip(key.iter().cycle())
+        .for_each(|(b, k)| *b ^= k);
+}
+
+/// A fresh masking key. Clients have to pick a new, unpredictable key for every frame they send.
+pub(crate) fn new_mask_key(random: &mut dyn RandomSource) -> [u8; 4] {
+    let mut key = [0u8; 4];
+    random.fill(&mut key);
+    key
 }
 
 impl WebSocketFrame {
-    fn new_bin(
+    pub(crate) fn new_bin(
         fin: bool,
         opcode: WebSocketOpCode,
         data: Vec<u8>,
         mask_key: Option<[u8; 4]>,
     ) -> WebSocketFrame {
-        let len: u64 = (data.len() * 8).try_into().unwrap();
-        if mask_key.is_some() {
-            return WebSocketFrame {
-                fin,
-                masked: true,
-                opcode,
-                payload_len: len,
-                mask_key,
-                data,
-            };
-        }
+        // payload length is always in bytes, https://www.rfc-editor.org/rfc/rfc6455#section-5.2
+        let len: u64 = data.len().try_into().expect("usize fits in u64");
         WebSocketFrame {
             fin,
-            masked: false,
+            rsv: 0,
+            masked: mask_key.is_some(),
             opcode,
             payload_len: len,
             mask_key,
@@ -49,123 +208,168 @@ impl WebSocketFrame {
         }
     }
 
-    fn new_str(
+    #[cfg(test)]
+    pub(crate) fn new_str(
         fin: bool,
         opcode: WebSocketOpCode,
         data: String,
         mask_key: Option<[u8; 4]>,
     ) -> WebSocketFrame {
-        let len: u64 = (data.len() * 8).try_into().unwrap();
-        if mask_key.is_some() {
-            return WebSocketFrame {
-                fin,
-                masked: true,
-                opcode,
-                payload_len: len,
-                mask_key,
-                data: data.as_bytes().into(),
-            };
+        WebSocketFrame::new_bin(fin, opcode, data.into_bytes(), mask_key)
+    }
+
+    /// Split a message into a sequence of frames carrying at most `fragment_size` bytes of payload
+    /// each. The first frame carries `opcode`, the rest are continuations, and only the last has FIN
+    /// set, https://www.rfc-editor.org/rfc/rfc6455#section-5.4
+    /// Every frame gets its own masking key from `mask` when one is given.
+    pub(crate) fn fragment(
+        opcode: WebSocketOpCode,
+        data: &[u8],
+        fragment_size: usize,
+        mut mask: Option<&mut dyn RandomSource>,
+    ) -> Vec<WebSocketFrame> {
+        let mut key = || mask.as_mut().map(|random| new_mask_key(*random));
+        if data.len() <= fragment_size || fragment_size == 0 {
+            return vec![WebSocketFrame::new_bin(true, opcode, data.to_vec(), key())];
         }
-        WebSocketFrame {
-            fin,
-            masked: false,
-            opcode,
-            payload_len: len,
-            mask_key,
-            data: data.as_bytes().into(),
+
+        let chunk_count = data.len().div_ceil(fragment_size);
+        data.chunks(fragment_size)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let opcode = if i == 0 {
+                    opcode
+                } else {
+                    WebSocketOpCode::Continuation
+                };
+                WebSocketFrame::new_bin(i == chunk_count - 1, opcode, chunk.to_vec(), key())
+            })
+            .collect()
+    }
+
+    /// Reads the payload length field starting at the second byte of `raw`, returning the number
+    /// of header bytes up to and including the length field along with the length itself. `None`
+    /// if `raw` doesn't yet hold the full length field.
+    fn read_payload_len(raw: &[u8]) -> Result<Option<(usize, u64)>, FrameError> {
+        let shifted_len = match raw.get(1) {
+            Some(b) => b & !0x80,
+            None => return Ok(None),
+        };
+        let (len_end, payload_len) = match shifted_len {
+            0..=125 => (2, shifted_len.into()),
+            // extended lengths are in network byte order
+            126 => match raw.get(2..4).and_then(|b| <[u8; 2]>::try_from(b).ok()) {
+                Some(len_bytes) => (4, u16::from_be_bytes(len_bytes).into()),
+                None => return Ok(None),
+            },
+            _ => match raw.get(2..10).and_then(|b| <[u8; 8]>::try_from(b).ok()) {
+                // the most significant bit must be 0, which MAX_PAYLOAD_LEN already covers
+                Some(len_bytes) => (10, u64::from_be_bytes(len_bytes)),
+                None => return Ok(None),
+            },
+        };
+
+        // https://www.rfc-editor.org/rfc/rfc6455#section-5.2 - "the minimal number of bytes MUST
+        // be used to encode the length"
+        if (len_end == 4 && payload_len <= 125) || (len_end == 10 && payload_len <= 0xFFFF) {
+            return Err(FrameError::NonMinimalLength(payload_len));
+        }
+        if payload_len > MAX_PAYLOAD_LEN {
+            return Err(FrameError::PayloadTooLarge(payload_len));
         }
+
+        Ok(Some((len_end, payload_len)))
     }
+
+    /// Total size in bytes of the frame at the start of `raw`, or `None` if `raw` doesn't hold the
+    /// whole frame yet
+    fn frame_len(raw: &[u8]) -> Result<Option<usize>, FrameError> {
+        let Some((len_end, payload_len)) = Self::read_payload_len(raw)? else {
+            return Ok(None);
+        };
+        let masked = raw[1] >> 7 == 1;
+        let header_len = if masked { len_end + 4 } else { len_end };
+        let total = header_len + payload_len as usize;
+        Ok((raw.len() >= total).then_some(total))
+    }
+
     /// Parse a frame from raw bytes incoming on the wire
     /// https://www.rfc-editor.org/rfc/rfc6455#section-5.2
-    fn parse(raw: Vec<u8>) -> WebSocketFrame {
-        // FIXME: get rid of panics and expects and gracefully handle malformed frames
+    pub(crate) fn parse(raw: Vec<u8>) -> Result<WebSocketFrame, FrameError> {
+        let (meta, mask_and_len) = match raw[..] {
+            [meta, mask_and_len, ..] => (meta, mask_and_len),
+            _ => return Err(FrameError::TruncatedHeader),
+        };
 
-        let mut handle = VecDeque::from(raw);
+        // first byte is metadata: fin bit, 3 reserved, opcode
+        let fin = meta >> 7 == 1;
 
-        // first byte is metadata: fin bit, 2 reserved, opcode
-        let meta = handle.pop_front().expect("frame contained fin and opcode");
-        let fin = match meta >> 7 {
-            0 => false,
-            1 => true,
-            _ => panic!("failed bitshift"),
-        };
+        // whether the reserved bits are allowed depends on the extensions in use, which is up to
+        // the decoder
+        let rsv = (meta >> 4) & 0x07;
 
-        let opcode = match meta & !0xF0 {
+        let opcode = match meta & 0x0F {
             0x0 => WebSocketOpCode::Continuation,
             0x1 => WebSocketOpCode::Text,
             0x2 => WebSocketOpCode::Binary,
-            0x3..=0x7 => WebSocketOpCode::Reserved,
             0x8 => WebSocketOpCode::Close,
             0x9 => WebSocketOpCode::Ping,
             0xA => WebSocketOpCode::Pong,
-            0xB..=0xF => WebSocketOpCode::Reserved,
-            _ => panic!("failed mask"),
+            reserved => return Err(FrameError::ReservedOpCode(reserved)),
         };
 
-        let mask_and_len = handle
-            .pop_front()
-            .expect("frame contained mask flag and initial length");
-        let masked = match mask_and_len >> 7 {
-            0 => false,
-            1 => true,
-            _ => panic!("failed bitshift"),
-        };
+        let masked = mask_and_len >> 7 == 1;
 
-        let shifted_len = mask_and_len & !0x80;
-        let payload_len: u64 = match shifted_len {
-            0..=125 => shifted_len.into(),
-            126 => {
-                let mut len_bytes: u64 = handle.pop_front().expect("length bytes").into();
-                len_bytes += u64::from(handle.pop_front().expect("length bytes"));
-                len_bytes
+        // https://www.rfc-editor.org/rfc/rfc6455#section-5.5 - control frames must not be
+        // fragmented and must fit their payload into the single length byte
+        if opcode.is_control() {
+            if !fin {
+                return Err(FrameError::FragmentedControlFrame);
             }
-            127 => {
-                // TODO: there's a clever way to do this with an iterator and a take(8) but I can't
-                // find it right now
-                let mut len_bytes: u64 = 0;
-                (0..8).for_each(|_| {
-                    len_bytes += u64::from(handle.pop_front().expect("length bytes"));
-                });
-                len_bytes
+            if mask_and_len & 0x7F > 125 {
+                return Err(FrameError::OversizedControlFrame);
             }
-            _ => panic!(),
-        };
+        }
 
+        let (len_end, payload_len) =
+            Self::read_payload_len(&raw)?.ok_or(FrameError::TruncatedHeader)?;
+
+        let mut data_start = len_end;
         let mask_key = if masked {
-            let mask: [u8; 4] = handle
-                .drain(0..4)
-                .collect::<Vec<u8>>()
-                .try_into()
-                .expect("4 byte mask key");
+            let mask: [u8; 4] = raw
+                .get(len_end..len_end + 4)
+                .and_then(|key| key.try_into().ok())
+                .ok_or(FrameError::TruncatedHeader)?;
+            data_start += 4;
             Some(mask)
         } else {
             None
         };
 
-        let data = match payload_len {
-            0 => vec![],
-            _ => {
-                let mut data_buf = vec![0u8; (payload_len / 8) as usize];
-                handle.read_exact(&mut data_buf).expect("read length bytes");
-                data_buf
-            }
-        };
+        let mut data = raw
+            .get(data_start..data_start + payload_len as usize)
+            .ok_or(FrameError::TruncatedPayload)?
+            .to_vec();
+        if let Some(key) = mask_key {
+            apply_mask(&mut data, key);
+        }
 
-        WebSocketFrame {
+        Ok(WebSocketFrame {
             fin,
+            rsv,
             masked,
             opcode,
             payload_len,
             mask_key,
             data,
-        }
+        })
     }
 
-    fn encode(self) -> Vec<u8> {
+    pub(crate) fn encode(self) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::new();
 
-        // first byte is fin + empty + opcode most significant -> least significant
-        let mut meta: u8 = 0;
+        // first byte is fin + rsv + opcode most significant -> least significant
+        let mut meta: u8 = (self.rsv & 0x07) << 4;
 
         if self.fin {
             meta |= 0x80;
@@ -178,7 +382,6 @@ impl WebSocketFrame {
             WebSocketOpCode::Close => meta |= 0x08,
             WebSocketOpCode::Ping => meta |= 0x09,
             WebSocketOpCode::Pong => meta |= 0x0A,
-            WebSocketOpCode::Reserved => meta |= 0x0F,
         };
 
         bytes.push(meta);
@@ -196,18 +399,19 @@ impl WebSocketFrame {
                     bytes.push(len)
                 }
             }
-            126 => {
+            126..=0xFFFF => {
                 if self.masked {
                     bytes.push(126 | 0x80)
                 } else {
                     bytes.push(126)
                 }
 
-                // push 2 more bytes representing the length
-                // FIXME: This is all kinds of unsafe and makes plenty of assumptions
-                let le = self.payload_len.to_le_bytes();
-                bytes.push(le[0]);
-                bytes.push(le[1]);
+                // push 2 more bytes representing the length, big endian
+                let len: u16 = self
+                    .payload_len
+                    .try_into()
+                    .expect("length fits in two bytes");
+                bytes.extend_from_slice(&len.to_be_bytes());
             }
             _ => {
                 if self.masked {
@@ -216,8 +420,7 @@ impl WebSocketFrame {
                     bytes.push(127)
                 }
 
-                let le = self.payload_len.to_le_bytes();
-                le.iter().for_each(|b| bytes.push(*b));
+                bytes.extend_from_slice(&self.payload_len.to_be_bytes());
             }
         }
 
@@ -226,8 +429,11 @@ impl WebSocketFrame {
             key.iter().for_each(|b| bytes.push(*b));
         }
 
-        // TODO: might be able to get away without the clone here
-        bytes.append(&mut self.data.clone());
+        let mut data = self.data;
+        if let Some(key) = self.mask_key {
+            apply_mask(&mut data, key);
+        }
+        bytes.append(&mut data);
 
         bytes
     }
@@ -241,7 +447,7 @@ mod tests {
     fn encode_and_parse_empty() {
         let frame = WebSocketFrame::new_bin(true, WebSocketOpCode::Continuation, vec![], None);
         let binary = frame.encode();
-        let parsed = WebSocketFrame::parse(binary);
+        let parsed = WebSocketFrame::parse(binary).unwrap();
         assert!(parsed.fin);
         assert!(!parsed.masked);
         assert_eq!(parsed.opcode, WebSocketOpCode::Continuation);
@@ -255,26 +461,337 @@ mod tests {
         let frame =
             WebSocketFrame::new_bin(true, WebSocketOpCode::Continuation, vec![1, 2, 3], None);
         let binary = frame.encode();
-        let parsed = WebSocketFrame::parse(binary);
+        let parsed = WebSocketFrame::parse(binary).unwrap();
         assert!(parsed.fin);
         assert!(!parsed.masked);
         assert_eq!(parsed.opcode, WebSocketOpCode::Continuation);
-        assert_eq!(parsed.payload_len, 24);
+        assert_eq!(parsed.payload_len, 3);
         assert_eq!(parsed.mask_key, None);
         assert_eq!(parsed.data, vec![1, 2, 3]);
     }
 
+    #[test]
+    fn encode_and_parse_rsv() {
+        let mut frame = WebSocketFrame::new_bin(true, WebSocketOpCode::Text, vec![1], None);
+        frame.rsv = 0b101;
+        let binary = frame.encode();
+        assert_eq!(binary[0], 0xD1);
+        assert_eq!(WebSocketFrame::parse(binary).unwrap().rsv, 0b101);
+    }
+
     #[test]
     fn encode_and_parse_simple_str() {
         let frame =
             WebSocketFrame::new_str(true, WebSocketOpCode::Continuation, "foo".to_string(), None);
         let binary = frame.encode();
-        let parsed = WebSocketFrame::parse(binary);
+        let parsed = WebSocketFrame::parse(binary).unwrap();
         assert!(parsed.fin);
         assert!(!parsed.masked);
         assert_eq!(parsed.opcode, WebSocketOpCode::Continuation);
-        assert_eq!(parsed.payload_len, 24);
+        assert_eq!(parsed.payload_len, 3);
         assert_eq!(parsed.mask_key, None);
         assert_eq!(parsed.data, vec![102, 111, 111]);
     }
+
+    #[test]
+    fn decode_split_frame() {
+        let binary =
+            WebSocketFrame::new_bin(true, WebSocketOpCode::Binary, vec![1, 2, 3], None).encode();
+        let mut decoder = FrameDecoder::new(None);
+
+        // hand the frame over one byte at a time, nothing comes out until the last byte
+        let (last, head) = binary.split_last().unwrap();
+        head.iter()
+            .for_each(|b| assert!(decoder.feed(&[*b]).unwrap().is_empty()));
+        let frames = decoder.feed(&[*last]).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].opcode, WebSocketOpCode::Binary);
+        assert_eq!(frames[0].data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn decode_coalesced_frames() {
+        let mut binary =
+            WebSocketFrame::new_str(false, WebSocketOpCode::Text, "foo".to_string(), None).encode();
+        binary.append(
+            &mut WebSocketFrame::new_str(
+                true,
+                WebSocketOpCode::Continuation,
+                "bar".to_string(),
+                None,
+            )
+            .encode(),
+        );
+        let mut third =
+            WebSocketFrame::new_bin(true, WebSocketOpCode::Ping, vec![9], None).encode();
+        let tail = third.split_off(1);
+        binary.append(&mut third);
+
+        let mut decoder = FrameDecoder::new(None);
+        let frames = decoder.feed(&binary).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(!frames[0].fin);
+        assert_eq!(frames[0].data, b"foo".to_vec());
+        assert!(frames[1].fin);
+        assert_eq!(frames[1].opcode, WebSocketOpCode::Continuation);
+        assert_eq!(frames[1].data, b"bar".to_vec());
+
+        // the partial ping is held until the rest of it shows up
+        let frames = decoder.feed(&tail).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].opcode, WebSocketOpCode::Ping);
+        assert_eq!(frames[0].data, vec![9]);
+    }
+
+    #[test]
+    fn decode_masked_frame_across_reads() {
+        let binary =
+            WebSocketFrame::new_bin(true, WebSocketOpCode::Text, vec![4, 5], Some([1, 2, 3, 4]))
+                .encode();
+        let mut decoder = FrameDecoder::new(None);
+        assert!(decoder.feed(&binary[..4]).unwrap().is_empty());
+        let frames = decoder.feed(&binary[4..]).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].masked);
+        assert_eq!(frames[0].mask_key, Some([1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn parse_truncated() {
+        assert_eq!(
+            WebSocketFrame::parse(vec![]).unwrap_err(),
+            FrameError::TruncatedHeader
+        );
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x82]).unwrap_err(),
+            FrameError::TruncatedHeader
+        );
+        // masked, but only two bytes of the key
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x82, 0x80, 1, 2]).unwrap_err(),
+            FrameError::TruncatedHeader
+        );
+        // 126 length form missing its extended length
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x82, 126, 1]).unwrap_err(),
+            FrameError::TruncatedHeader
+        );
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x82, 2, 1]).unwrap_err(),
+            FrameError::TruncatedPayload
+        );
+    }
+
+    #[test]
+    fn parse_reserved() {
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x83, 0]).unwrap_err(),
+            FrameError::ReservedOpCode(0x3)
+        );
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x8F, 0]).unwrap_err(),
+            FrameError::ReservedOpCode(0xF)
+        );
+        assert_eq!(
+            FrameDecoder::new(None).feed(&[0xC1, 0]).unwrap_err(),
+            FrameError::ReservedBits(0b100)
+        );
+        assert_eq!(
+            FrameDecoder::new(None).feed(&[0x91, 0]).unwrap_err(),
+            FrameError::ReservedBits(0b001)
+        );
+
+        // bits claimed by an extension are let through, the rest still aren't
+        let mut decoder = FrameDecoder::new(None);
+        decoder.allow_rsv(0b100);
+        assert_eq!(decoder.feed(&[0xC1, 0]).unwrap()[0].rsv, 0b100);
+        assert_eq!(
+            decoder.feed(&[0xE1, 0]).unwrap_err(),
+            FrameError::ReservedBits(0b010)
+        );
+    }
+
+    #[test]
+    fn parse_bad_control_frames() {
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x09, 0]).unwrap_err(),
+            FrameError::FragmentedControlFrame
+        );
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x88, 126, 0, 126]).unwrap_err(),
+            FrameError::OversizedControlFrame
+        );
+    }
+
+    #[test]
+    fn parse_bad_lengths() {
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x82, 126, 0, 5]).unwrap_err(),
+            FrameError::NonMinimalLength(5)
+        );
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x82, 127, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF]).unwrap_err(),
+            FrameError::NonMinimalLength(0xFFFF)
+        );
+        assert_eq!(
+            WebSocketFrame::parse(vec![0x82, 127, 0x80, 0, 0, 0, 0, 0, 0, 0]).unwrap_err(),
+            FrameError::PayloadTooLarge(1 << 63)
+        );
+    }
+
+    #[test]
+    fn decode_bad_mask() {
+        let unmasked = WebSocketFrame::new_bin(true, WebSocketOpCode::Text, vec![1], None).encode();
+        let masked =
+            WebSocketFrame::new_bin(true, WebSocketOpCode::Text, vec![1], Some([1, 2, 3, 4]))
+                .encode();
+
+        assert_eq!(
+            FrameDecoder::new(Some(true)).feed(&unmasked).unwrap_err(),
+            FrameError::BadMask
+        );
+        assert_eq!(
+            FrameDecoder::new(Some(false)).feed(&masked).unwrap_err(),
+            FrameError::BadMask
+        );
+        assert!(FrameDecoder::new(Some(true)).feed(&masked).is_ok());
+    }
+
+    #[test]
+    fn close_codes() {
+        assert_eq!(FrameError::TruncatedHeader.close_code(), 1002);
+        assert_eq!(FrameError::ReservedOpCode(3).close_code(), 1002);
+        assert_eq!(FrameError::BadMask.close_code(), 1002);
+        assert_eq!(FrameError::UnexpectedContinuation.close_code(), 1002);
+        assert_eq!(FrameError::PayloadTooLarge(1 << 40).close_code(), 1009);
+        assert_eq!(FrameError::MessageTooLarge(1 << 30).close_code(), 1009);
+        assert_eq!(FrameError::InvalidUtf8.close_code(), 1007);
+        assert_eq!(FrameError::InvalidCloseCode(1005).close_code(), 1002);
+    }
+
+    #[test]
+    fn length_boundaries() {
+        // (payload length, expected length header bytes after the first byte)
+        let cases: Vec<(usize, Vec<u8>)> = vec![
+            (0, vec![0]),
+            (125, vec![125]),
+            (126, vec![126, 0, 126]),
+            (127, vec![126, 0, 127]),
+            (256, vec![126, 1, 0]),
+            (65535, vec![126, 0xFF, 0xFF]),
+            (65536, vec![127, 0, 0, 0, 0, 0, 1, 0, 0]),
+            (70000, vec![127, 0, 0, 0, 0, 0, 1, 0x11, 0x70]),
+        ];
+
+        for (len, header) in cases {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let binary =
+                WebSocketFrame::new_bin(true, WebSocketOpCode::Binary, data.clone(), None).encode();
+            assert_eq!(binary[1..1 + header.len()], header[..], "length {}", len);
+            assert_eq!(binary.len(), 1 + header.len() + len);
+
+            let parsed = WebSocketFrame::parse(binary.clone()).unwrap();
+            assert_eq!(parsed.payload_len, len as u64);
+            assert_eq!(parsed.data, data);
+
+            // and the same again through the decoder, split at the end of the header
+            let mut decoder = FrameDecoder::new(None);
+            assert!(decoder.feed(&binary[..header.len()]).unwrap().is_empty());
+            let frames = decoder.feed(&binary[header.len()..]).unwrap();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].data, data);
+        }
+    }
+
+    #[test]
+    fn masking() {
+        // example from https://www.rfc-editor.org/rfc/rfc6455#section-5.7
+        let frame = WebSocketFrame::new_str(
+            true,
+            WebSocketOpCode::Text,
+            "Hello".to_string(),
+            Some([0x37, 0xfa, 0x21, 0x3d]),
+        );
+        let binary = frame.encode();
+        assert_eq!(
+            binary,
+            vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]
+        );
+
+        let parsed = WebSocketFrame::parse(binary).unwrap();
+        assert!(parsed.masked);
+        assert_eq!(parsed.mask_key, Some([0x37, 0xfa, 0x21, 0x3d]));
+        assert_eq!(parsed.data, b"Hello".to_vec());
+    }
+
+    #[test]
+    fn masking_long_payload() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 256) as u8).collect();
+        let binary = WebSocketFrame::new_bin(
+            true,
+            WebSocketOpCode::Binary,
+            data.clone(),
+            Some(new_mask_key(&mut SystemRandom)),
+        )
+        .encode();
+        let frames = FrameDecoder::new(Some(true)).feed(&binary).unwrap();
+        assert_eq!(frames[0].data, data);
+    }
+
+    #[test]
+    fn fragment_small_message() {
+        let frames =
+            WebSocketFrame::fragment(WebSocketOpCode::Text, b"foo", 16, Some(&mut SystemRandom));
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].fin);
+        assert!(frames[0].masked);
+        assert_eq!(frames[0].opcode, WebSocketOpCode::Text);
+        assert_eq!(frames[0].data, b"foo".to_vec());
+
+        let frames = WebSocketFrame::fragment(WebSocketOpCode::Binary, &[], 16, None);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].fin);
+        assert!(!frames[0].masked);
+        assert!(frames[0].data.is_empty());
+    }
+
+    #[test]
+    fn fragment_large_message() {
+        let data: Vec<u8> = (0..40).collect();
+        let frames =
+            WebSocketFrame::fragment(WebSocketOpCode::Binary, &data, 16, Some(&mut SystemRandom));
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            frames.iter().map(|f| f.opcode).collect::<Vec<_>>(),
+            vec![
+                WebSocketOpCode::Binary,
+                WebSocketOpCode::Continuation,
+                WebSocketOpCode::Continuation
+            ]
+        );
+        assert_eq!(
+            frames.iter().map(|f| f.fin).collect::<Vec<_>>(),
+            vec![false, false, true]
+        );
+        assert!(frames.iter().all(|f| f.masked));
+        assert_eq!(
+            frames.iter().map(|f| f.data.len()).collect::<Vec<_>>(),
+            vec![16, 16, 8]
+        );
+
+        // survives the trip over the wire
+        let binary: Vec<u8> = frames.into_iter().flat_map(|f| f.encode()).collect();
+        let parsed = FrameDecoder::new(Some(true)).feed(&binary).unwrap();
+        let joined: Vec<u8> = parsed.into_iter().flat_map(|f| f.data).collect();
+        assert_eq!(joined, data);
+    }
+
+    #[test]
+    fn fragment_exact_multiple() {
+        let data = [7u8; 32];
+        let frames = WebSocketFrame::fragment(WebSocketOpCode::Text, &data, 16, None);
+        assert_eq!(frames.len(), 2);
+        assert!(!frames[0].fin);
+        assert!(frames[1].fin);
+    }
 }
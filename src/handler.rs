@@ -0,0 +1,44 @@
+use crate::close::*;
+use crate::http::*;
+use crate::message::*;
+use crate::server::*;
+use crate::util::*;
+use std::net::TcpStream;
+
+/// Application callbacks for a `WebSocketServer`. One handler is shared by every connection, each
+/// callback gets the `ServerHandle` of the connection it's about, which can be used to reply or
+/// cloned to send from elsewhere. Every method has a default that does nothing, so only the
+/// interesting ones need implementing.
+pub trait Handler<S: Stream = TcpStream>: Send + Sync {
+    /// Called with a valid handshake before it's answered. Returning `false` turns the client away
+    /// with a 403 instead of upgrading the connection.
+    fn on_handshake(&self, _request: &HandshakeRequest) -> bool {
+        true
+    }
+
+    /// Called once the connection is open and messages can be sent
+    fn on_connect(&self, _handle: &mut ServerHandle<S>, _request: &HandshakeRequest) {}
+
+    /// Called with every Text and Binary message
+    fn on_message(&self, _handle: &mut ServerHandle<S>, _message: Message) {}
+
+    /// Called with the payload of every Ping, after it has already been answered with a Pong
+    fn on_ping(&self, _handle: &mut ServerHandle<S>, _data: &[u8]) {}
+
+    /// Called when the connection fails, either because the client broke the protocol or the
+    /// stream errored. `on_close` still follows.
+    fn on_error(&self, _handle: &mut ServerHandle<S>, _err: &std::io::Error) {}
+
+    /// Called once the connection is closed, with the status it closed with
+    fn on_close(&self, _handle: &mut ServerHandle<S>, _close: Option<CloseFrame>) {}
+}
+
+/// A plain closure is a handler that only cares about messages
+impl<S: Stream, F> Handler<S> for F
+where
+    F: Fn(&mut ServerHandle<S>, Message) + Send + Sync,
+{
+    fn on_message(&self, handle: &mut ServerHandle<S>, message: Message) {
+        self(handle, message)
+    }
+}
@@ -0,0 +1,204 @@
+use crate::random::*;
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Default time between keepalive Pings
+pub(crate) const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// Default time a Ping can go unanswered before the peer is considered dead
+pub(crate) const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Keepalive state for one connection: Pings are sent on an interval from a background thread and
+/// the connection's reader reports the Pongs that come back.
+/// https://www.rfc-editor.org/rfc/rfc6455#section-5.5.2
+pub(crate) struct Heartbeat {
+    /// Payload and send time of every Ping that hasn't been answered yet, oldest first
+    outstanding: Mutex<VecDeque<(Vec<u8>, Instant)>>,
+    timeout: Duration,
+    /// Dropped to wake up and stop the background thread
+    stop: Mutex<Option<mpsc::Sender<()>>>,
+    /// Source of the Ping payloads
+    random: Mutex<Box<dyn RandomSource>>,
+}
+
+impl Heartbeat {
+    fn new(
+        timeout: Duration,
+        random: impl RandomSource + 'static,
+    ) -> (Heartbeat, mpsc::Receiver<()>) {
+        let (stop, stopped) = mpsc::channel();
+        (
+            Heartbeat {
+                outstanding: Mutex::new(VecDeque::new()),
+                timeout,
+                stop: Mutex::new(Some(stop)),
+                random: Mutex::new(Box::new(random)),
+            },
+            stopped,
+        )
+    }
+
+    /// Spawn the background thread. Every `interval` it either sends a Ping through `send_ping`,
+    /// or calls `on_dead` and stops if a Ping has gone unanswered for longer than `timeout`. The
+    /// thread also stops once `send_ping` fails or `stop` is called. Ping payloads are drawn from
+    /// `random`.
+    pub(crate) fn start<R, P, D>(
+        interval: Duration,
+        timeout: Duration,
+        random: R,
+        mut send_ping: P,
+        on_dead: D,
+    ) -> Arc<Heartbeat>
+    where
+        R: RandomSource + 'static,
+        P: FnMut(Vec<u8>) -> std::io::Result<()> + Send + 'static,
+        D: FnOnce() + Send + 'static,
+    {
+        let (heartbeat, stopped) = Heartbeat::new(timeout, random);
+        let heartbeat = Arc::new(heartbeat);
+        let state = heartbeat.clone();
+        std::thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+            if state.is_expired(Instant::now()) {
+                on_dead();
+                return;
+            }
+            if send_ping(state.next_ping()).is_err() {
+                return;
+            }
+        });
+        heartbeat
+    }
+
+    pub(crate) fn stop(&self) {
+        self.stop.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+
+    /// Random payload for the next Ping, remembered until a matching Pong arrives
+    fn next_ping(&self) -> Vec<u8> {
+        let mut payload = [0u8; 8];
+        self.random
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .fill(&mut payload);
+        self.outstanding
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push_back((payload.to_vec(), Instant::now()));
+        payload.to_vec()
+    }
+
+    /// Record a Pong, returning whether it answered an outstanding Ping. Peers are allowed to
+    /// only answer the most recent of several Pings, so a match also clears every older Ping.
+    /// Unsolicited Pongs are ignored.
+    pub(crate) fn pong(&self, payload: &[u8]) -> bool {
+        let mut outstanding = self.outstanding.lock().unwrap_or_else(|e| e.into_inner());
+        match outstanding.iter().position(|(ping, _)| ping == payload) {
+            Some(i) => {
+                outstanding.drain(..=i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the oldest unanswered Ping has been waiting longer than the timeout
+    fn is_expired(&self, now: Instant) -> bool {
+        self.outstanding
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .front()
+            .is_some_and(|(_, sent)| now.duration_since(*sent) > self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pong_matching() {
+        let seeded = || ChaCha20Rng::from_seed([1; 32]);
+        let (heartbeat, _stopped) = Heartbeat::new(Duration::from_secs(10), seeded());
+        let first = heartbeat.next_ping();
+        let second = heartbeat.next_ping();
+        let third = heartbeat.next_ping();
+        // the same seed gives the same payloads
+        let (again, _stopped) = Heartbeat::new(Duration::from_secs(10), seeded());
+        assert_eq!(again.next_ping(), first);
+        assert_ne!(first, second);
+
+        assert!(!heartbeat.pong(b"unsolicited"));
+        assert!(heartbeat.pong(&second));
+        // answering the second also covered the first
+        assert!(!heartbeat.pong(&first));
+        assert!(heartbeat.pong(&third));
+        assert!(!heartbeat.pong(&third));
+    }
+
+    #[test]
+    fn expiry() {
+        let (heartbeat, _stopped) = Heartbeat::new(Duration::from_secs(10), SystemRandom);
+        let now = Instant::now();
+        assert!(!heartbeat.is_expired(now + Duration::from_secs(60)));
+
+        let ping = heartbeat.next_ping();
+        assert!(!heartbeat.is_expired(now + Duration::from_secs(5)));
+        assert!(heartbeat.is_expired(now + Duration::from_secs(11)));
+
+        heartbeat.pong(&ping);
+        assert!(!heartbeat.is_expired(now + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn dead_peer() {
+        let (pings, sent) = mpsc::channel();
+        let (dead, died) = mpsc::channel();
+        let _heartbeat = Heartbeat::start(
+            Duration::from_millis(10),
+            Duration::from_millis(15),
+            SystemRandom,
+            move |payload| {
+                pings.send(payload).unwrap();
+                Ok(())
+            },
+            move || dead.send(()).unwrap(),
+        );
+
+        // nobody answers, so after a Ping or two the peer is declared dead
+        let first = sent.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(first.len(), 8);
+        died.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn answered_pings_keep_alive() {
+        let (dead, died) = mpsc::channel();
+        let heartbeat: Arc<Mutex<Option<Arc<Heartbeat>>>> = Arc::new(Mutex::new(None));
+        let answerer = heartbeat.clone();
+        let started = Heartbeat::start(
+            Duration::from_millis(10),
+            Duration::from_millis(15),
+            SystemRandom,
+            move |payload| {
+                if let Some(heartbeat) = answerer.lock().unwrap().as_ref() {
+                    heartbeat.pong(&payload);
+                }
+                Ok(())
+            },
+            move || dead.send(()).unwrap(),
+        );
+        *heartbeat.lock().unwrap() = Some(started.clone());
+
+        assert!(died.recv_timeout(Duration::from_millis(100)).is_err());
+        started.stop();
+    }
+}
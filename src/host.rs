@@ -0,0 +1,194 @@
+use std::net::Ipv6Addr;
+
+/// Which names the server answers to, going by the Host header of a handshake
+/// https://www.rfc-editor.org/rfc/rfc6455#section-4.2.1
+/// e.g. `HostPolicy::new().allow("example.com").allow("localhost:4024").allow("[::1]:4024")`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPolicy {
+    any: bool,
+    /// Normalized host names, with the port they're restricted to if one was given
+    allowed: Vec<(String, Option<u16>)>,
+}
+
+impl Default for HostPolicy {
+    fn default() -> HostPolicy {
+        HostPolicy::any()
+    }
+}
+
+impl HostPolicy {
+    /// Answer to no host until some are allowed
+    pub fn new() -> HostPolicy {
+        HostPolicy {
+            any: false,
+            allowed: Vec::new(),
+        }
+    }
+
+    /// Answer to any host, the default. The Host header still has to be present and well formed.
+    pub fn any() -> HostPolicy {
+        HostPolicy {
+            any: true,
+            allowed: Vec::new(),
+        }
+    }
+
+    /// Answer to `host`, a name or IP address with an optional port like `example.com:8080` or
+    /// `[::1]:4024`. Without a port every port is accepted.
+    ///
+    /// # Panics
+    /// If `host` can't be parsed
+    pub fn allow(mut self, host: &str) -> HostPolicy {
+        // IPv6 addresses are written bare when there's no port, brackets are optional
+        let host = match host.parse::<Ipv6Addr>() {
+            Ok(addr) => (format!("[{addr}]"), None),
+            Err(_) => parse_host(host).unwrap_or_else(|| panic!("Invalid host '{}'", host)),
+        };
+        self.any = false;
+        self.allowed.push(host);
+        self
+    }
+
+    /// Whether the value of a Host header is one the server answers to
+    pub(crate) fn accepts(&self, host: &str) -> bool {
+        let Some((name, port)) = parse_host(host) else {
+            return false;
+        };
+        self.any
+            || self.allowed.iter().any(|(allowed, allowed_port)| {
+                *allowed == name
+                    && match (allowed_port, port) {
+                        (None, _) => true,
+                        (Some(allowed_port), Some(port)) => *allowed_port == port,
+                        // leaving the port out means the default one for ws:// or wss://
+                        (Some(allowed_port), None) => [80, 443].contains(allowed_port),
+                    }
+            })
+    }
+}
+
+/// Split a Host header value into its lowercased name and port. IPv6 addresses keep their
+/// brackets and are written the canonical way, and a trailing dot on a name is dropped.
+/// https://www.rfc-editor.org/rfc/rfc9110#section-7.2
+fn parse_host(host: &str) -> Option<(String, Option<u16>)> {
+    let (name, port) = match host.strip_prefix('[') {
+        Some(rest) => {
+            let (addr, port) = rest.split_once(']')?;
+            let addr = addr.parse::<Ipv6Addr>().ok()?;
+            let port = match port {
+                "" => None,
+                port => Some(port.strip_prefix(':')?),
+            };
+            (format!("[{addr}]"), port)
+        }
+        None => {
+            let (name, port) = match host.split_once(':') {
+                Some((name, port)) => (name, Some(port)),
+                None => (host, None),
+            };
+            let name = name.strip_suffix('.').unwrap_or(name);
+            let valid = |c: char| c.is_ascii_alphanumeric() || "-._~!$&'()*+,;=%".contains(c);
+            if name.is_empty() || !name.chars().all(valid) {
+                return None;
+            }
+            (name.to_ascii_lowercase(), port)
+        }
+    };
+    let port = match port {
+        None => None,
+        Some(port) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            Some(port.parse::<u16>().ok()?)
+        }
+        Some(_) => return None,
+    };
+    Some((name, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_parsing() {
+        assert_eq!(
+            parse_host("Example.COM:8080"),
+            Some((String::from("example.com"), Some(8080)))
+        );
+        assert_eq!(
+            parse_host("example.com."),
+            Some((String::from("example.com"), None))
+        );
+        assert_eq!(
+            parse_host("[::0001]:4024"),
+            Some((String::from("[::1]"), Some(4024)))
+        );
+        assert_eq!(
+            parse_host("[2001:DB8::1]"),
+            Some((String::from("[2001:db8::1]"), None))
+        );
+        assert_eq!(
+            parse_host("127.0.0.1:4024"),
+            Some((String::from("127.0.0.1"), Some(4024)))
+        );
+        for invalid in [
+            "",
+            ":4024",
+            "example.com:",
+            "example.com:http",
+            "example.com:65536",
+            "example.com:80:80",
+            "exa mple.com",
+            "user@example.com",
+            "::1",
+            "[::1",
+            "[::1]4024",
+            "[example.com]",
+        ] {
+            assert_eq!(parse_host(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn allowed_hosts() {
+        let policy = HostPolicy::new()
+            .allow("example.com")
+            .allow("LOCALHOST:4024")
+            .allow("::1")
+            .allow("[fe80::1]:4024")
+            .allow("secure.example.com:443");
+
+        for host in [
+            "example.com",
+            "EXAMPLE.com:8080",
+            "example.com.",
+            "localhost:4024",
+            "[::1]",
+            "[0:0::1]:4024",
+            "[FE80::1]:4024",
+            "secure.example.com",
+            "secure.example.com:443",
+        ] {
+            assert!(policy.accepts(host), "{}", host);
+        }
+        for host in [
+            "evil.example",
+            "sub.example.com",
+            "localhost",
+            "localhost:4025",
+            "[::2]",
+            "[fe80::1]",
+            "secure.example.com:8443",
+            "example.com:http",
+        ] {
+            assert!(!policy.accepts(host), "{}", host);
+        }
+    }
+
+    #[test]
+    fn any_host() {
+        let policy = HostPolicy::default();
+        assert!(policy.accepts("example.com"));
+        assert!(policy.accepts("[::1]:4024"));
+        assert!(!policy.accepts("exa mple.com"));
+    }
+}
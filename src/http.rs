@@ -0,0 +1,765 @@
+use crate::extension::*;
+use std::{collections::HashMap, fmt, net::SocketAddr};
+
+/// A client's opening handshake, as handed to the application once it's been validated
+/// https://www.rfc-editor.org/rfc/rfc6455#section-4.2.1
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HandshakeRequest {
+    /// Always `GET` for a valid handshake
+    pub method: String,
+    /// Path of the Request-URI, without the query string
+    pub path: String,
+    /// Decoded query string values, e.g. `?room=lobby&token=abc`
+    pub query: HashMap<String, String>,
+    /// Path parameters captured by the `Router` pattern that matched, e.g. `id` from `/rooms/:id`
+    pub params: HashMap<String, String>,
+    /// e.g. `HTTP/1.1`
+    pub version: String,
+    pub headers: HeaderMap,
+    /// Cookies from every Cookie header. The first of several with the same name wins, which is
+    /// the one with the most specific path, https://www.rfc-editor.org/rfc/rfc6265#section-5.4
+    pub cookies: HashMap<String, String>,
+    /// Address of the client, or of the proxy in front of it
+    pub peer_addr: Option<SocketAddr>,
+}
+
+impl HandshakeRequest {
+    /// First value of the header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Subprotocols the client offered in Sec-WebSocket-Protocol, most preferred first
+    pub fn protocols(&self) -> Vec<&str> {
+        self.headers.get_list("sec-websocket-protocol")
+    }
+
+    /// Extensions the client offered in Sec-WebSocket-Extensions, most preferred first
+    pub fn extensions(&self) -> Result<Vec<ExtensionOffer>, String> {
+        parse_extensions(&self.headers.get_all("sec-websocket-extensions").join(", "))
+    }
+}
+
+/// Header fields looked up by name, ignoring case. A header sent on several lines keeps each of
+/// its values, https://www.rfc-editor.org/rfc/rfc9110#section-5.3
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HeaderMap {
+    /// Names lowercased, in the order they arrived
+    fields: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap { fields: Vec::new() }
+    }
+
+    /// Add a value for `name`, after any it already has
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields
+            .push((name.to_ascii_lowercase(), value.to_string()));
+    }
+
+    /// First value of the header called `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).into_iter().next()
+    }
+
+    /// Every value of the header called `name`, in the order they were sent
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        let name = name.to_ascii_lowercase();
+        self.fields
+            .iter()
+            .filter(|(field, _)| *field == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    /// Entries of a comma separated header like `Sec-WebSocket-Protocol: chat, superchat`, across
+    /// every line it was sent on
+    pub fn get_list(&self, name: &str) -> Vec<&str> {
+        self.get_all(name)
+            .into_iter()
+            .flat_map(split_tokens)
+            .collect()
+    }
+
+    /// Value of a header that may only be given once, https://www.rfc-editor.org/rfc/rfc9110#section-5.3
+    pub(crate) fn get_unique(&self, name: &str) -> Result<Option<&str>, String> {
+        match self.get_all(name)[..] {
+            [] => Ok(None),
+            [value] => Ok(Some(value)),
+            _ => Err(format!("Handshake contains more than one {} header", name)),
+        }
+    }
+
+    /// Whether the comma separated header `name` lists `token` on any of its lines, ignoring case.
+    /// Browsers send things like `Connection: keep-alive, Upgrade`,
+    /// https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1
+    pub(crate) fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_list(name)
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(token))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Every name and value, names lowercased
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+impl From<Vec<(String, String)>> for HeaderMap {
+    fn from(fields: Vec<(String, String)>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &fields {
+            headers.append(name, value);
+        }
+        headers
+    }
+}
+
+/// Most bytes the request line and header fields of a handshake may take up
+pub(crate) const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/// https://www.rfc-editor.org/rfc/rfc9110#section-5.6.2
+pub(crate) fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// The start line and header fields of an HTTP/1.1 message
+/// https://www.rfc-editor.org/rfc/rfc9112#section-2.1
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct MessageHead {
+    pub(crate) start_line: String,
+    /// Names lowercased, in the order they arrived, repeats included
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl MessageHead {
+    /// Method, request target and HTTP version of a request line
+    /// https://www.rfc-editor.org/rfc/rfc9112#section-3
+    pub(crate) fn request_line(&self) -> Result<(&str, &str, &str), String> {
+        match self.start_line.split(' ').collect::<Vec<_>>()[..] {
+            [method, target, version]
+                if !method.is_empty() && !target.is_empty() && !version.is_empty() =>
+            {
+                Ok((method, target, version))
+            }
+            _ => Err(String::from("Handshake is not a valid HTTP request")),
+        }
+    }
+}
+
+impl fmt::Display for MessageHead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.start_line)?;
+        for (name, value) in &self.headers {
+            write!(f, "\n{}: {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects an HTTP/1.1 message head as it's read off the stream, however it's split up
+/// https://www.rfc-editor.org/rfc/rfc9112#section-2.2
+#[derive(Debug)]
+pub(crate) struct HeadParser {
+    buf: Vec<u8>,
+    /// Bytes of empty lines in front of the head, dropped from `buf` but counted toward `max_len`
+    skipped: usize,
+    /// How far into `buf` has been searched for the empty line that ends the head
+    scanned: usize,
+    max_len: usize,
+}
+
+impl HeadParser {
+    pub(crate) fn new(max_len: usize) -> HeadParser {
+        HeadParser {
+            buf: Vec::new(),
+            skipped: 0,
+            scanned: 0,
+            max_len,
+        }
+    }
+
+    /// Add bytes from the stream. Once the empty line that ends the head has arrived, returns the
+    /// head along with whatever bytes came after it, which belong to the WebSocket connection.
+    /// Lines can end in CRLF or a bare LF.
+    pub(crate) fn feed(&mut self, bytes: &[u8]) -> Result<Option<(MessageHead, Vec<u8>)>, String> {
+        self.buf.extend_from_slice(bytes);
+        while let Some(offset) = self.buf[self.scanned..].iter().position(|b| *b == b'\n') {
+            let line_start = self.scanned;
+            self.scanned += offset + 1;
+            if !matches!(&self.buf[line_start..self.scanned], b"\n" | b"\r\n") {
+                continue;
+            }
+            // empty lines in front of the request line are skipped
+            if line_start == 0 {
+                self.skipped += self.scanned;
+                self.buf.drain(..self.scanned);
+                self.scanned = 0;
+                if self.skipped > self.max_len {
+                    break;
+                }
+                continue;
+            }
+            if self.skipped + line_start > self.max_len {
+                break;
+            }
+            let head = parse_head(&self.buf[..line_start])?;
+            return Ok(Some((head, self.buf.split_off(self.scanned))));
+        }
+        if self.skipped + self.buf.len() > self.max_len {
+            return Err(format!(
+                "Handshake headers are larger than {} bytes",
+                self.max_len
+            ));
+        }
+        Ok(None)
+    }
+}
+
+/// Parse the lines of a head, each ending in a line feed
+fn parse_head(bytes: &[u8]) -> Result<MessageHead, String> {
+    let text = std::str::from_utf8(bytes)
+        .map_err(|_| String::from("Failed to parse handshake as utf8"))?;
+    let mut lines = text
+        .strip_suffix('\n')
+        .unwrap_or(text)
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line));
+
+    let start_line = lines.next().unwrap_or_default().to_string();
+    let mut head = MessageHead {
+        start_line,
+        headers: Vec::new(),
+    };
+    for line in std::iter::once(head.start_line.as_str()).chain(lines.clone()) {
+        if line.contains(['\r', '\0']) {
+            return Err(String::from("Handshake contains a stray CR or NUL"));
+        }
+    }
+    for line in lines {
+        // https://www.rfc-editor.org/rfc/rfc9112#section-5.2
+        if line.starts_with([' ', '\t']) {
+            return Err(String::from("Handshake uses obsolete header line folding"));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(format!("Malformed header line '{}'", line));
+        };
+        // no whitespace is allowed between the name and colon, https://www.rfc-editor.org/rfc/rfc9112#section-5.1
+        if name.is_empty() || !name.chars().all(is_tchar) {
+            return Err(format!("Invalid header name '{}'", name));
+        }
+        head.headers.push((
+            name.to_lowercase(),
+            value.trim_matches([' ', '\t']).to_string(),
+        ));
+    }
+    Ok(head)
+}
+
+/// Headers the server works out itself for a 101, which applications can't add their own of
+const UPGRADE_HEADERS: [&str; 7] = [
+    "upgrade",
+    "connection",
+    "sec-websocket-accept",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+    "content-length",
+    "transfer-encoding",
+];
+/// Headers the server works out itself for any other response
+const REFUSAL_HEADERS: [&str; 3] = ["connection", "content-length", "transfer-encoding"];
+
+/// An HTTP/1.1 response to a handshake, https://www.rfc-editor.org/rfc/rfc9112#section-4
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    /// Plain text explaining a refusal
+    pub(crate) body: String,
+}
+
+impl Response {
+    pub(crate) fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    pub(crate) fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub(crate) fn with_body(mut self, body: &str) -> Response {
+        self.body = body.to_string();
+        self
+    }
+
+    /// Add headers an application asked for. Fails if one could smuggle extra lines into the
+    /// response or replace a header the server has to write itself.
+    pub(crate) fn with_custom_headers(
+        mut self,
+        headers: &[(String, String)],
+    ) -> Result<Response, String> {
+        let reserved: &[&str] = match self.status {
+            101 => &UPGRADE_HEADERS,
+            _ => &REFUSAL_HEADERS,
+        };
+        for (name, value) in headers {
+            if name.is_empty() || !name.chars().all(is_tchar) {
+                return Err(format!(
+                    "Invalid response header name '{}'",
+                    name.escape_debug()
+                ));
+            }
+            if value.contains(['\r', '\n', '\0']) {
+                return Err(format!("Response header {} contains a CR, LF or NUL", name));
+            }
+            if reserved.iter().any(|r| name.eq_ignore_ascii_case(r)) {
+                return Err(format!(
+                    "Response header {} can only be set by the server",
+                    name
+                ));
+            }
+            self = self.with_header(name, value.trim());
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn bad_request(reason: &str) -> Response {
+        Response::new(400).with_body(reason)
+    }
+
+    /// https://www.rfc-editor.org/rfc/rfc9110#section-15
+    fn reason_phrase(&self) -> &'static str {
+        match self.status {
+            101 => "Switching Protocols",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+
+    /// The status line, headers and body as they go on the wire. Anything but a 101 gets a
+    /// Content-Length and closes the connection, since there's nothing more to say after it.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason_phrase());
+        for (name, value) in &self.headers {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        if self.status != 101 {
+            let has_type = self
+                .headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("content-type"));
+            if !self.body.is_empty() && !has_type {
+                head += "Content-Type: text/plain; charset=utf-8\r\n";
+            }
+            head += &format!("Content-Length: {}\r\n", self.body.len());
+            head += "Connection: close\r\n";
+        }
+        head += "\r\n";
+        let mut encoded = head.into_bytes();
+        encoded.extend_from_slice(self.body.as_bytes());
+        encoded
+    }
+}
+
+/// The entries of a comma separated header value like `chat, superchat`, with surrounding
+/// whitespace and empty entries dropped
+/// https://www.rfc-editor.org/rfc/rfc9110#section-5.6.1
+pub(crate) fn split_tokens(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(|token| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+/// Name and value pairs of every Cookie header, https://www.rfc-editor.org/rfc/rfc6265#section-4.2
+pub(crate) fn parse_cookies(headers: &HeaderMap) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for pair in headers
+        .get_all("cookie")
+        .into_iter()
+        .flat_map(|c| c.split(';'))
+    {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        let value = value.trim();
+        // values can be wrapped in quotes, which aren't part of them
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        if !name.is_empty() {
+            cookies
+                .entry(name.to_string())
+                .or_insert_with(|| value.to_string());
+        }
+    }
+    cookies
+}
+
+/// Split a Request-URI into its path and decoded query values. Later repeats of a query key
+/// replace earlier ones.
+pub(crate) fn split_request_uri(uri: &str) -> (String, HashMap<String, String>) {
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                percent_decode(&name.replace('+', " ")),
+                percent_decode(&value.replace('+', " ")),
+            )
+        })
+        .collect();
+    (path.to_string(), query)
+}
+
+/// Decode `%XX` escapes, leaving malformed ones as they are. Escapes that don't decode to UTF-8
+/// come out as U+FFFD.
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_uri() {
+        let (path, query) = split_request_uri("/ws");
+        assert_eq!(path, "/ws");
+        assert!(query.is_empty());
+
+        let (path, query) = split_request_uri("/rooms/1?name=J%C3%A9+B&flag&empty=&&a=1&a=2");
+        assert_eq!(path, "/rooms/1");
+        assert_eq!(query.len(), 4);
+        assert_eq!(query["name"], "Jé B");
+        assert_eq!(query["flag"], "");
+        assert_eq!(query["empty"], "");
+        assert_eq!(query["a"], "2");
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            split_tokens(" chat,superchat ,, v2.chat ").collect::<Vec<_>>(),
+            vec!["chat", "superchat", "v2.chat"]
+        );
+        assert_eq!(split_tokens("").count(), 0);
+
+        let mut headers = HeaderMap::new();
+        headers.append("Connection", "keep-alive");
+        headers.append("connection", "Upgrade, foo");
+        assert!(headers.has_token("CONNECTION", "upgrade"));
+        assert!(!headers.has_token("connection", "close"));
+        assert!(!headers.has_token("upgrade", "websocket"));
+
+        let mut request = HandshakeRequest::default();
+        assert!(request.protocols().is_empty());
+        request
+            .headers
+            .append("Sec-WebSocket-Protocol", "chat, superchat");
+        assert_eq!(request.protocols(), vec!["chat", "superchat"]);
+        request.headers.append("sec-websocket-protocol", "v2.chat");
+        assert_eq!(request.protocols(), vec!["chat", "superchat", "v2.chat"]);
+    }
+
+    #[test]
+    fn custom_headers() {
+        let custom = |status: u16, name: &str, value: &str| {
+            Response::new(status).with_custom_headers(&[(name.to_string(), value.to_string())])
+        };
+        assert_eq!(
+            custom(101, "Set-Cookie", "session=abc; HttpOnly"),
+            Ok(Response::new(101).with_header("Set-Cookie", "session=abc; HttpOnly"))
+        );
+        assert_eq!(
+            custom(401, "WWW-Authenticate", " Bearer "),
+            Ok(Response::new(401).with_header("WWW-Authenticate", "Bearer"))
+        );
+        // Connection: close is still up to the server, but a 101 says nothing about a body
+        assert!(custom(403, "Sec-WebSocket-Accept", "x").is_ok());
+
+        assert_eq!(
+            custom(101, "X-Trace", "1\r\nSet-Cookie: admin=1"),
+            Err(String::from(
+                "Response header X-Trace contains a CR, LF or NUL"
+            ))
+        );
+        assert!(custom(101, "X-Trace", "1\n").is_err());
+        assert!(custom(403, "X-Trace", "\0").is_err());
+        assert_eq!(
+            custom(101, "X-Trace\r\nSet-Cookie", "admin=1"),
+            Err(String::from(
+                "Invalid response header name 'X-Trace\\r\\nSet-Cookie'"
+            ))
+        );
+        assert!(custom(101, "", "x").is_err());
+        assert!(custom(101, "X Trace", "x").is_err());
+        assert!(custom(101, "X-Trace:", "x").is_err());
+        for name in [
+            "Sec-WebSocket-Accept",
+            "sec-websocket-protocol",
+            "Sec-WebSocket-Extensions",
+            "UPGRADE",
+            "Connection",
+            "Content-Length",
+            "Transfer-Encoding",
+        ] {
+            assert_eq!(
+                custom(101, name, "x"),
+                Err(format!(
+                    "Response header {} can only be set by the server",
+                    name
+                ))
+            );
+        }
+        for name in ["Connection", "content-length", "Transfer-Encoding"] {
+            assert!(custom(401, name, "x").is_err());
+        }
+    }
+
+    #[test]
+    fn header_map() {
+        let headers = HeaderMap::from(vec![
+            (String::from("Host"), String::from("localhost")),
+            (String::from("Cookie"), String::from("a=1")),
+            (String::from("X-Empty"), String::new()),
+            (String::from("COOKIE"), String::from("b=2")),
+        ]);
+        assert_eq!(headers.get("HOST"), Some("localhost"));
+        assert_eq!(headers.get("cookie"), Some("a=1"));
+        assert_eq!(headers.get_all("Cookie"), vec!["a=1", "b=2"]);
+        assert_eq!(headers.get("x-empty"), Some(""));
+        assert!(headers.contains("X-EMPTY"));
+        assert_eq!(headers.get_unique("host"), Ok(Some("localhost")));
+        assert_eq!(headers.get_unique("missing"), Ok(None));
+        assert_eq!(
+            headers.get_unique("Cookie"),
+            Err(String::from(
+                "Handshake contains more than one Cookie header"
+            ))
+        );
+        assert!(!headers.contains("missing"));
+        assert!(headers.get_all("missing").is_empty());
+        assert_eq!(
+            headers.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            vec!["host", "cookie", "x-empty", "cookie"]
+        );
+    }
+
+    #[test]
+    fn cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append("Cookie", "session=abc123; theme=\"dark\"; =nameless; flag");
+        headers.append("Cookie", " lang = en ;session=older");
+        let cookies = parse_cookies(&headers);
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies["session"], "abc123");
+        assert_eq!(cookies["theme"], "dark");
+        assert_eq!(cookies["lang"], "en");
+        assert!(parse_cookies(&HeaderMap::new()).is_empty());
+    }
+
+    fn parse_all(raw: &[u8]) -> Result<Option<(MessageHead, Vec<u8>)>, String> {
+        HeadParser::new(MAX_HEAD_LEN).feed(raw)
+    }
+
+    #[test]
+    fn head() {
+        let (head, rest) = parse_all(
+            b"GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, Upgrade\r\n\
+              X-Empty:\r\nCONNECTION:\t  extra \r\n\r\n\x81\x00",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(head.request_line(), Ok(("GET", "/ws", "HTTP/1.1")));
+        assert_eq!(
+            head.headers,
+            vec![
+                (String::from("host"), String::from("localhost")),
+                (
+                    String::from("connection"),
+                    String::from("keep-alive, Upgrade")
+                ),
+                (String::from("x-empty"), String::new()),
+                (String::from("connection"), String::from("extra")),
+            ]
+        );
+        assert_eq!(rest, vec![0x81, 0x00]);
+
+        // bare line feeds and empty lines in front
+        let (head, rest) = parse_all(b"\r\n\nGET / HTTP/1.1\nHost: a\n\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(head.start_line, "GET / HTTP/1.1");
+        assert_eq!(
+            head.headers,
+            vec![(String::from("host"), String::from("a"))]
+        );
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn incremental_head() {
+        let raw = b"GET /ws HTTP/1.1\r\nHost: localhost\r\n\r\nrest";
+        let mut parser = HeadParser::new(MAX_HEAD_LEN);
+        for byte in &raw[..raw.len() - 5] {
+            assert_eq!(parser.feed(&[*byte]), Ok(None));
+        }
+        let (head, rest) = parser.feed(&raw[raw.len() - 5..]).unwrap().unwrap();
+        assert_eq!(
+            head.headers,
+            vec![(String::from("host"), String::from("localhost"))]
+        );
+        assert_eq!(rest, b"rest".to_vec());
+    }
+
+    #[test]
+    fn invalid_head() {
+        for (raw, error) in [
+            (
+                &b"GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n"[..],
+                "Handshake uses obsolete header line folding",
+            ),
+            (
+                b"GET / HTTP/1.1\r\n Host: a\r\n\r\n",
+                "Handshake uses obsolete header line folding",
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost : a\r\n\r\n",
+                "Invalid header name 'Host '",
+            ),
+            (b"GET / HTTP/1.1\r\n: a\r\n\r\n", "Invalid header name ''"),
+            (
+                b"GET / HTTP/1.1\r\nHost\r\n\r\n",
+                "Malformed header line 'Host'",
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: a\rb\r\n\r\n",
+                "Handshake contains a stray CR or NUL",
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n",
+                "Failed to parse handshake as utf8",
+            ),
+        ] {
+            assert_eq!(parse_all(raw), Err(String::from(error)));
+        }
+
+        for line in ["GET /", "GET  / HTTP/1.1", "GET / HTTP/1.1 extra", ""] {
+            let head = MessageHead {
+                start_line: String::from(line),
+                headers: Vec::new(),
+            };
+            assert!(head.request_line().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn head_size_limit() {
+        let mut parser = HeadParser::new(64);
+        assert_eq!(parser.feed(b"GET / HTTP/1.1\r\n"), Ok(None));
+        assert_eq!(
+            parser.feed(&[b'a'; 64]),
+            Err(String::from("Handshake headers are larger than 64 bytes"))
+        );
+        // a complete head over the limit is refused too
+        let mut raw = b"GET / HTTP/1.1\r\nX: ".to_vec();
+        raw.extend_from_slice(&[b'a'; 64]);
+        raw.extend_from_slice(b"\r\n\r\n");
+        assert_eq!(
+            HeadParser::new(64).feed(&raw),
+            Err(String::from("Handshake headers are larger than 64 bytes"))
+        );
+        // as are endless empty lines in front of the request line
+        let mut parser = HeadParser::new(MAX_HEAD_LEN);
+        assert_eq!(parser.feed(&b"\r\n".repeat(MAX_HEAD_LEN / 4)), Ok(None));
+        assert_eq!(
+            parser.feed(&b"\r\n".repeat(MAX_HEAD_LEN / 2)),
+            Err(format!(
+                "Handshake headers are larger than {} bytes",
+                MAX_HEAD_LEN
+            ))
+        );
+    }
+
+    #[test]
+    fn responses() {
+        let response = Response::new(101)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade");
+        assert_eq!(
+            response.encode(),
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n"
+                .to_vec()
+        );
+
+        assert_eq!(
+            String::from_utf8(Response::bad_request("Bad key").encode()).unwrap(),
+            "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 7\r\nConnection: close\r\n\r\nBad key"
+        );
+        assert_eq!(
+            String::from_utf8(Response::new(404).encode()).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+        // the length is in bytes, not characters
+        assert!(
+            String::from_utf8(Response::new(403).with_body("é").encode())
+                .unwrap()
+                .contains("Content-Length: 2\r\n")
+        );
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%FF"), "\u{FFFD}");
+        assert_eq!(percent_decode("a+b"), "a+b");
+    }
+}
@@ -0,0 +1,22 @@
+pub mod auth;
+mod base64;
+pub mod client;
+pub mod close;
+pub mod connection;
+mod deflate;
+pub mod extension;
+mod frame;
+pub mod handler;
+mod heartbeat;
+pub mod host;
+pub mod http;
+mod log;
+pub mod message;
+pub mod origin;
+pub mod permessage_deflate;
+pub mod random;
+pub mod router;
+pub mod server;
+mod sha1;
+mod utf8;
+pub mod util;
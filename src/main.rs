@@ -1,12 +1,30 @@
-use client::*;
-use server::*;
-use std::env;
+use rhubarb::client::*;
+use rhubarb::handler::*;
+use rhubarb::message::*;
+use rhubarb::permessage_deflate::*;
+use rhubarb::router::*;
+use rhubarb::server::*;
+use std::{env, io::BufRead, sync::Arc};
 
-mod client;
-mod frame;
-mod log;
-mod server;
-mod util;
+/// Prints and echoes back whatever clients send
+struct Echo;
+
+impl Handler for Echo {
+    fn on_message(&self, handle: &mut ServerHandle, message: Message) {
+        _ = match message {
+            Message::Text(text) => {
+                print!(
+                    "{} - {}",
+                    handle.peer_addr().expect("peer address found"),
+                    text
+                );
+                handle.send_text(&text)
+            }
+            Message::Binary(data) => handle.send_binary(&data),
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) => Ok(()),
+        };
+    }
+}
 
 fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
@@ -16,8 +34,10 @@ fn main() -> std::io::Result<()> {
     let run_mode = &args[1];
 
     if run_mode.to_lowercase() == "server" {
-        let server = WebSocketServer::create("127.0.0.1:4024")?;
-        server.listen()
+        let mut server = WebSocketServer::create("127.0.0.1:4024")?;
+        server.protocols = vec![String::from("rhubarb")];
+        server.extensions = vec![Arc::new(PerMessageDeflate::default())];
+        server.listen(Router::new().route("/ws", Echo))
     } else if run_mode.to_lowercase() == "client" {
         let bind_addr: &str = if args.len() < 3 {
             "127.0.0.1:4024"
@@ -26,6 +46,8 @@ fn main() -> std::io::Result<()> {
         };
 
         let mut client = WebSocketClient::create(bind_addr)?;
+        client.protocols = vec![String::from("rhubarb")];
+        client.extensions = vec![Arc::new(PerMessageDeflate::default())];
         // TODO: let this path be an arg to the cli
         client.perform_handshake(String::from("/ws"))?;
 
@@ -33,13 +55,17 @@ fn main() -> std::io::Result<()> {
         let receiver = client.clone();
         let handle = std::thread::spawn(|| receiver.recv());
 
-        // now read user stdin and send that for all eternity
-        let mut stdin_buf = String::new();
-        let stdin = std::io::stdin();
-        while stdin.read_line(&mut stdin_buf)? != 0 {
-            _ = client.send(stdin_buf.as_bytes());
+        // now read user stdin and send that for all eternity, as text unless it isn't utf8
+        let mut stdin_buf = Vec::new();
+        let mut stdin = std::io::stdin().lock();
+        while stdin.read_until(b'\n', &mut stdin_buf)? != 0 {
+            _ = match std::str::from_utf8(&stdin_buf) {
+                Ok(text) => client.send_text(text),
+                Err(_) => client.send_binary(&stdin_buf),
+            };
             stdin_buf.clear();
         }
+        client.close(1000, "")?;
         Ok(handle.join().expect("closing client receiver")?)
     } else {
         panic!("Must give arg as 'client' or 'server'")
@@ -0,0 +1,325 @@
+use crate::close::*;
+use crate::extension::*;
+use crate::frame::*;
+use crate::utf8::*;
+use std::sync::{Arc, Mutex};
+
+/// Largest message rhubarb will reassemble before failing the connection with 1009
+pub(crate) const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// A complete message, or a control frame's payload
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+/// Joins a data frame and the continuation frames that follow it back into a single message
+/// https://www.rfc-editor.org/rfc/rfc6455#section-5.4
+#[derive(Default)]
+pub(crate) struct MessageAssembler {
+    /// Opcode, RSV bits of the first frame, and payload so far of the message that has started but
+    /// not seen FIN yet
+    partial: Option<(WebSocketOpCode, u8, Vec<u8>)>,
+    /// Checks Text messages fragment by fragment so bad input fails the connection early
+    utf8: Utf8Validator,
+    /// Undone on every complete message before it's handed out
+    extensions: Arc<Mutex<ExtensionChain>>,
+}
+
+impl MessageAssembler {
+    #[cfg(test)]
+    pub(crate) fn new() -> MessageAssembler {
+        MessageAssembler::with_extensions(Arc::default())
+    }
+
+    pub(crate) fn with_extensions(extensions: Arc<Mutex<ExtensionChain>>) -> MessageAssembler {
+        MessageAssembler {
+            partial: None,
+            utf8: Utf8Validator::new(),
+            extensions,
+        }
+    }
+
+    /// Push the next frame off the wire. Returns a message once `frame` completes one; control
+    /// frames are allowed in the middle of a fragmented message and come straight back out.
+    pub(crate) fn push(&mut self, frame: WebSocketFrame) -> Result<Option<Message>, FrameError> {
+        // extensions only ever apply to whole messages, so the RSV bits can only be set on the
+        // first frame of one
+        if frame.rsv != 0
+            && (frame.opcode.is_control() || frame.opcode == WebSocketOpCode::Continuation)
+        {
+            return Err(FrameError::ReservedBits(frame.rsv));
+        }
+
+        match frame.opcode {
+            WebSocketOpCode::Ping => return Ok(Some(Message::Ping(frame.data))),
+            WebSocketOpCode::Pong => return Ok(Some(Message::Pong(frame.data))),
+            WebSocketOpCode::Close => {
+                return CloseFrame::parse(&frame.data).map(|close| Some(Message::Close(close)))
+            }
+            WebSocketOpCode::Text | WebSocketOpCode::Binary => {
+                if self.partial.is_some() {
+                    return Err(FrameError::ExpectedContinuation);
+                }
+                self.partial = Some((frame.opcode, frame.rsv, Vec::new()));
+            }
+            WebSocketOpCode::Continuation => {
+                if self.partial.is_none() {
+                    return Err(FrameError::UnexpectedContinuation);
+                }
+            }
+        };
+
+        let (opcode, rsv, data) = self.partial.as_mut().expect("message in progress");
+        if data.len() + frame.data.len() > MAX_MESSAGE_LEN {
+            return Err(FrameError::MessageTooLarge(data.len() + frame.data.len()));
+        }
+        // a message with RSV bits set has been transformed by an extension, so its text can only be
+        // checked once that's been undone
+        if *opcode == WebSocketOpCode::Text && *rsv == 0 && !self.utf8.feed(&frame.data) {
+            return Err(FrameError::InvalidUtf8);
+        }
+        data.extend_from_slice(&frame.data);
+
+        if !frame.fin {
+            return Ok(None);
+        }
+
+        let (opcode, rsv, data) = self.partial.take().expect("message in progress");
+        let mut message = ExtensionMessage {
+            text: opcode == WebSocketOpCode::Text,
+            rsv,
+            data,
+        };
+        {
+            let mut extensions = self.extensions.lock().unwrap_or_else(|e| e.into_inner());
+            if !extensions.is_empty() {
+                extensions
+                    .incoming(&mut message)
+                    .map_err(FrameError::Extension)?;
+            }
+        }
+        if message.data.len() > MAX_MESSAGE_LEN {
+            return Err(FrameError::MessageTooLarge(message.data.len()));
+        }
+
+        match message.text {
+            true => {
+                if !self.utf8.finish() {
+                    return Err(FrameError::InvalidUtf8);
+                }
+                String::from_utf8(message.data)
+                    .map(|text| Some(Message::Text(text)))
+                    .map_err(|_| FrameError::InvalidUtf8)
+            }
+            false => Ok(Some(Message::Binary(message.data))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(fin: bool, opcode: WebSocketOpCode, data: &[u8]) -> WebSocketFrame {
+        WebSocketFrame::new_bin(fin, opcode, data.to_vec(), None)
+    }
+
+    #[test]
+    fn unfragmented() {
+        let mut assembler = MessageAssembler::new();
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Text, b"foo")),
+            Ok(Some(Message::Text(String::from("foo"))))
+        );
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Binary, &[1, 2])),
+            Ok(Some(Message::Binary(vec![1, 2])))
+        );
+    }
+
+    #[test]
+    fn fragmented_with_interleaved_control() {
+        let mut assembler = MessageAssembler::new();
+        assert_eq!(
+            assembler.push(frame(false, WebSocketOpCode::Text, b"He")),
+            Ok(None)
+        );
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Ping, b"p")),
+            Ok(Some(Message::Ping(b"p".to_vec())))
+        );
+        assert_eq!(
+            assembler.push(frame(false, WebSocketOpCode::Continuation, b"l")),
+            Ok(None)
+        );
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Pong, b"")),
+            Ok(Some(Message::Pong(vec![])))
+        );
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Continuation, b"lo")),
+            Ok(Some(Message::Text(String::from("Hello"))))
+        );
+
+        // and the next message starts fresh
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Binary, &[3])),
+            Ok(Some(Message::Binary(vec![3])))
+        );
+    }
+
+    #[test]
+    fn round_trip_through_fragment() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut assembler = MessageAssembler::new();
+        let messages: Vec<Message> =
+            WebSocketFrame::fragment(WebSocketOpCode::Binary, &data, 7, None)
+                .into_iter()
+                .filter_map(|f| assembler.push(f).unwrap())
+                .collect();
+        assert_eq!(messages, vec![Message::Binary(data)]);
+    }
+
+    #[test]
+    fn continuation_without_message() {
+        let mut assembler = MessageAssembler::new();
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Continuation, b"foo")),
+            Err(FrameError::UnexpectedContinuation)
+        );
+    }
+
+    #[test]
+    fn data_frame_during_message() {
+        let mut assembler = MessageAssembler::new();
+        assert_eq!(
+            assembler.push(frame(false, WebSocketOpCode::Binary, b"foo")),
+            Ok(None)
+        );
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Text, b"bar")),
+            Err(FrameError::ExpectedContinuation)
+        );
+    }
+
+    #[test]
+    fn invalid_text() {
+        let mut assembler = MessageAssembler::new();
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Text, &[0xC3, 0x28])),
+            Err(FrameError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn invalid_text_fails_before_fin() {
+        let mut assembler = MessageAssembler::new();
+        assert_eq!(
+            assembler.push(frame(false, WebSocketOpCode::Text, b"ok so far")),
+            Ok(None)
+        );
+        assert_eq!(
+            assembler.push(frame(false, WebSocketOpCode::Continuation, &[0xFF])),
+            Err(FrameError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn code_point_split_across_fragments() {
+        let text = "\u{1f980}\u{1f980}".as_bytes();
+        let mut assembler = MessageAssembler::new();
+        assert_eq!(
+            assembler.push(frame(false, WebSocketOpCode::Text, &text[..3])),
+            Ok(None)
+        );
+        assert_eq!(
+            assembler.push(frame(false, WebSocketOpCode::Continuation, &text[3..5])),
+            Ok(None)
+        );
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Continuation, &text[5..])),
+            Ok(Some(Message::Text(String::from("\u{1f980}\u{1f980}"))))
+        );
+
+        // ending partway through a code point is invalid
+        assert_eq!(
+            assembler.push(frame(false, WebSocketOpCode::Text, &text[..3])),
+            Ok(None)
+        );
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Continuation, &[])),
+            Err(FrameError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn extensions() {
+        let chain = confirm(
+            &[ExtensionOffer::new("x-reverse")],
+            &[Arc::new(crate::extension::tests::Reverse)],
+        )
+        .unwrap();
+        let mut assembler = MessageAssembler::with_extensions(Arc::new(Mutex::new(chain)));
+
+        // reversed text isn't checked until it's been turned back around
+        let mut first = frame(false, WebSocketOpCode::Text, &[0xA6, 0xA6, 0x9F]);
+        first.rsv = RSV2;
+        assert_eq!(assembler.push(first), Ok(None));
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Continuation, &[0xF0, b'a'])),
+            Ok(Some(Message::Text(String::from("a\u{1f9a6}"))))
+        );
+
+        // the extension fails messages it didn't flag
+        assert_eq!(
+            assembler
+                .push(frame(true, WebSocketOpCode::Binary, &[1]))
+                .unwrap_err()
+                .close_code(),
+            1002
+        );
+
+        // RSV bits are only allowed on the first frame of a data message
+        let mut ping = frame(true, WebSocketOpCode::Ping, &[]);
+        ping.rsv = RSV2;
+        assert_eq!(assembler.push(ping), Err(FrameError::ReservedBits(RSV2)));
+        let mut first = frame(false, WebSocketOpCode::Binary, &[1]);
+        first.rsv = RSV2;
+        assert_eq!(assembler.push(first), Ok(None));
+        let mut continuation = frame(true, WebSocketOpCode::Continuation, &[2]);
+        continuation.rsv = RSV2;
+        assert_eq!(
+            assembler.push(continuation),
+            Err(FrameError::ReservedBits(RSV2))
+        );
+    }
+
+    #[test]
+    fn close_frames() {
+        let mut assembler = MessageAssembler::new();
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Close, &[])),
+            Ok(Some(Message::Close(None)))
+        );
+        assert_eq!(
+            assembler.push(frame(
+                true,
+                WebSocketOpCode::Close,
+                &[0x03, 0xE9, b'h', b'i']
+            )),
+            Ok(Some(Message::Close(Some(CloseFrame {
+                code: 1001,
+                reason: String::from("hi")
+            }))))
+        );
+        assert_eq!(
+            assembler.push(frame(true, WebSocketOpCode::Close, &[0x03, 0xED])),
+            Err(FrameError::InvalidCloseCode(1005))
+        );
+    }
+}
@@ -0,0 +1,203 @@
+/// Which web pages may open connections, going by the Origin header browsers put on every
+/// handshake. Without a policy any page a user visits could connect with the user's cookies.
+/// https://www.rfc-editor.org/rfc/rfc6455#section-10.2
+/// e.g. `OriginPolicy::new().allow("https://example.com").allow("https://*.example.com")`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginPolicy {
+    any: bool,
+    /// Lowercased `scheme://host[:port]`, where host can start with `*.` to match any subdomain
+    allowed: Vec<String>,
+    same_origin: bool,
+    allow_missing: bool,
+}
+
+impl Default for OriginPolicy {
+    fn default() -> OriginPolicy {
+        OriginPolicy::any()
+    }
+}
+
+impl OriginPolicy {
+    /// Let no origin in until some are allowed. Clients that send no Origin, which browsers
+    /// always do, are still let in.
+    pub fn new() -> OriginPolicy {
+        OriginPolicy {
+            any: false,
+            allowed: Vec::new(),
+            same_origin: false,
+            allow_missing: true,
+        }
+    }
+
+    /// Let every origin in, the default
+    pub fn any() -> OriginPolicy {
+        OriginPolicy {
+            any: true,
+            ..OriginPolicy::new()
+        }
+    }
+
+    /// Let in pages from `origin`, given as `scheme://host[:port]` like `https://example.com`.
+    /// A host of `*.example.com` matches every subdomain of example.com but not example.com itself.
+    pub fn allow(mut self, origin: &str) -> OriginPolicy {
+        self.any = false;
+        self.allowed
+            .push(origin.trim_end_matches('/').to_ascii_lowercase());
+        self
+    }
+
+    /// Let in pages served from the host the handshake was sent to
+    pub fn same_origin(mut self) -> OriginPolicy {
+        self.any = false;
+        self.same_origin = true;
+        self
+    }
+
+    /// Whether handshakes without an Origin header are let in
+    pub fn allow_missing(mut self, allow: bool) -> OriginPolicy {
+        self.allow_missing = allow;
+        self
+    }
+
+    /// Returns why a handshake from `origin`, sent to `host`, isn't allowed
+    pub(crate) fn check(&self, origin: Option<&str>, host: Option<&str>) -> Result<(), String> {
+        let origin = match origin {
+            Some(origin) => origin.to_ascii_lowercase(),
+            None if self.allow_missing => return Ok(()),
+            None => return Err(String::from("Handshake missing Origin header")),
+        };
+        if self.any
+            || self.allowed.iter().any(|allowed| matches(allowed, &origin))
+            || (self.same_origin && host.is_some_and(|host| is_same_origin(&origin, host)))
+        {
+            return Ok(());
+        }
+        Err(format!("Origin '{}' is not allowed", origin))
+    }
+}
+
+/// Whether the origin is `allowed`, or a subdomain of it when it has a `*.` host
+fn matches(allowed: &str, origin: &str) -> bool {
+    let (Some((scheme, host)), Some((origin_scheme, origin_host))) =
+        (allowed.split_once("://"), origin.split_once("://"))
+    else {
+        return false;
+    };
+    if scheme != origin_scheme {
+        return false;
+    }
+    match host.strip_prefix('*') {
+        // the suffix also covers the port, so a different one doesn't match
+        Some(suffix) if suffix.starts_with('.') => {
+            origin_host.strip_suffix(suffix).is_some_and(|subdomain| {
+                !subdomain.is_empty()
+                    && subdomain
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            })
+        }
+        _ => host == origin_host,
+    }
+}
+
+/// Whether the page's origin has the same host and port as the Host the handshake was sent to.
+/// The scheme can't be compared since the server doesn't know whether it's behind TLS.
+fn is_same_origin(origin: &str, host: &str) -> bool {
+    let Some((scheme, origin_host)) = origin.split_once("://") else {
+        return false;
+    };
+    let default_port = match scheme {
+        "http" => ":80",
+        "https" => ":443",
+        _ => "",
+    };
+    let host = host.to_ascii_lowercase();
+    let host = match default_port {
+        "" => host.as_str(),
+        port => host.strip_suffix(port).unwrap_or(&host),
+    };
+    origin_host == host
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn any_origin() {
+        let policy = OriginPolicy::default();
+        assert_eq!(policy.check(Some("https://evil.example"), None), Ok(()));
+        assert_eq!(policy.check(None, None), Ok(()));
+    }
+
+    #[test]
+    fn allow_list() {
+        let policy = OriginPolicy::new()
+            .allow("https://example.com")
+            .allow("http://localhost:8080/")
+            .allow("https://*.rhubarb.dev");
+
+        assert_eq!(policy.check(Some("https://example.com"), None), Ok(()));
+        assert_eq!(policy.check(Some("HTTPS://Example.COM"), None), Ok(()));
+        assert_eq!(policy.check(Some("http://localhost:8080"), None), Ok(()));
+        assert_eq!(policy.check(Some("https://chat.rhubarb.dev"), None), Ok(()));
+        assert_eq!(policy.check(Some("https://a.b.rhubarb.dev"), None), Ok(()));
+
+        for origin in [
+            "http://example.com",
+            "https://example.com:8443",
+            "https://example.com.evil.example",
+            "https://notexample.com",
+            "http://localhost:8081",
+            "https://rhubarb.dev",
+            "https://evilrhubarb.dev",
+            "http://chat.rhubarb.dev",
+            "https://chat.rhubarb.dev:8443",
+            "null",
+        ] {
+            assert_eq!(
+                policy.check(Some(origin), None),
+                Err(format!("Origin '{}' is not allowed", origin))
+            );
+        }
+    }
+
+    #[test]
+    fn same_origin() {
+        let policy = OriginPolicy::new().same_origin();
+
+        assert_eq!(
+            policy.check(Some("http://localhost:4024"), Some("localhost:4024")),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(Some("https://example.com"), Some("Example.com:443")),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(Some("http://example.com"), Some("example.com")),
+            Ok(())
+        );
+        assert!(policy
+            .check(Some("http://localhost:8080"), Some("localhost:4024"))
+            .is_err());
+        assert!(policy
+            .check(Some("https://example.com"), Some("example.com:80"))
+            .is_err());
+        assert!(policy.check(Some("http://localhost:4024"), None).is_err());
+    }
+
+    #[test]
+    fn missing_origin() {
+        let policy = OriginPolicy::new().allow("https://example.com");
+        assert_eq!(policy.check(None, None), Ok(()));
+        assert_eq!(
+            policy.allow_missing(false).check(None, None),
+            Err(String::from("Handshake missing Origin header"))
+        );
+        assert!(OriginPolicy::any()
+            .allow_missing(false)
+            .check(None, None)
+            .is_err());
+    }
+}
@@ -0,0 +1,505 @@
+use crate::deflate::*;
+use crate::extension::*;
+use crate::message::MAX_MESSAGE_LEN;
+
+/// Every compressed message ends with an empty stored block, which is left off on the wire
+/// https://www.rfc-editor.org/rfc/rfc7692#section-7.2.1
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+/// The permessage-deflate extension, https://www.rfc-editor.org/rfc/rfc7692
+///
+/// Messages are compressed with DEFLATE and flagged with RSV1. The same settings serve both sides
+/// of the handshake: a server applies the `server_*` ones to itself and asks clients for the
+/// `client_*` ones where the client's offer allows it, while a client offers all of them.
+pub struct PerMessageDeflate {
+    /// Largest LZ77 window, as a power of two from 8 to 15, the server compresses with
+    pub server_max_window_bits: u8,
+    /// Largest LZ77 window, as a power of two from 8 to 15, the client compresses with
+    pub client_max_window_bits: u8,
+    /// Compress every server message on its own, rather than letting it refer back to earlier ones
+    pub server_no_context_takeover: bool,
+    /// Compress every client message on its own, rather than letting it refer back to earlier ones
+    pub client_no_context_takeover: bool,
+    /// Messages shorter than this many bytes are sent uncompressed
+    pub threshold: usize,
+}
+
+impl Default for PerMessageDeflate {
+    fn default() -> PerMessageDeflate {
+        PerMessageDeflate {
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            threshold: 128,
+        }
+    }
+}
+
+/// The parameters of an offer or response, https://www.rfc-editor.org/rfc/rfc7692#section-7.1
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Params {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: Option<u8>,
+    /// Offers can give client_max_window_bits without a value
+    client_max_window_bits: Option<Option<u8>>,
+}
+
+/// Window bits are 8 to 15 written without leading zeros
+fn window_bits(name: &str, value: Option<&str>) -> Result<u8, String> {
+    value
+        .filter(|v| !v.starts_with('0'))
+        .and_then(|v| v.parse::<u8>().ok())
+        .filter(|bits| (8..=15).contains(bits))
+        .ok_or_else(|| format!("permessage-deflate {} must be from 8 to 15", name))
+}
+
+fn parse_params(offer: &ExtensionOffer) -> Result<Params, String> {
+    let mut params = Params::default();
+    for (i, (name, value)) in offer.params.iter().enumerate() {
+        if offer.params[..i].iter().any(|(earlier, _)| earlier == name) {
+            return Err(format!("permessage-deflate {} given twice", name));
+        }
+        let value = value.as_deref();
+        match name.as_str() {
+            "server_no_context_takeover" | "client_no_context_takeover" if value.is_some() => {
+                return Err(format!("permessage-deflate {} takes no value", name));
+            }
+            "server_no_context_takeover" => params.server_no_context_takeover = true,
+            "client_no_context_takeover" => params.client_no_context_takeover = true,
+            "server_max_window_bits" => {
+                params.server_max_window_bits = Some(window_bits(name, value)?)
+            }
+            "client_max_window_bits" => {
+                params.client_max_window_bits = Some(match value {
+                    None => None,
+                    Some(_) => Some(window_bits(name, value)?),
+                })
+            }
+            _ => return Err(format!("Unknown permessage-deflate parameter {}", name)),
+        }
+    }
+    Ok(params)
+}
+
+impl Extension for PerMessageDeflate {
+    fn name(&self) -> &str {
+        "permessage-deflate"
+    }
+
+    fn rsv_bits(&self) -> u8 {
+        RSV1
+    }
+
+    fn offer(&self) -> ExtensionOffer {
+        let mut offer = ExtensionOffer::new(self.name());
+        if self.server_no_context_takeover {
+            offer = offer.with_param("server_no_context_takeover", None);
+        }
+        if self.client_no_context_takeover {
+            offer = offer.with_param("client_no_context_takeover", None);
+        }
+        if self.server_max_window_bits < 15 {
+            let bits = self.server_max_window_bits.to_string();
+            offer = offer.with_param("server_max_window_bits", Some(&bits));
+        }
+        // always offered, so the server knows it can ask for a smaller window
+        let bits = self.client_max_window_bits.to_string();
+        offer.with_param(
+            "client_max_window_bits",
+            (self.client_max_window_bits < 15).then_some(bits.as_str()),
+        )
+    }
+
+    fn accept(&self, offer: &ExtensionOffer) -> Option<(ExtensionOffer, Box<dyn Extension>)> {
+        let params = parse_params(offer).ok()?;
+        let mut response = ExtensionOffer::new(self.name());
+
+        let server_no_context_takeover =
+            self.server_no_context_takeover || params.server_no_context_takeover;
+        if server_no_context_takeover {
+            response = response.with_param("server_no_context_takeover", None);
+        }
+        let client_no_context_takeover =
+            self.client_no_context_takeover || params.client_no_context_takeover;
+        if client_no_context_takeover {
+            response = response.with_param("client_no_context_takeover", None);
+        }
+
+        let server_bits = self
+            .server_max_window_bits
+            .min(params.server_max_window_bits.unwrap_or(15));
+        if server_bits < 15 || params.server_max_window_bits.is_some() {
+            let bits = server_bits.to_string();
+            response = response.with_param("server_max_window_bits", Some(&bits));
+        }
+        // the client can only be asked for a smaller window if it said it supports one
+        if let Some(offered) = params.client_max_window_bits {
+            let client_bits = self.client_max_window_bits.min(offered.unwrap_or(15));
+            if client_bits < 15 {
+                let bits = client_bits.to_string();
+                response = response.with_param("client_max_window_bits", Some(&bits));
+            }
+        }
+
+        let instance = DeflateStream {
+            deflater: Deflater::new(server_bits, !server_no_context_takeover),
+            inflater: Inflater::new(!client_no_context_takeover),
+            threshold: self.threshold,
+        };
+        Some((response, Box::new(instance)))
+    }
+
+    fn accepted(&self, response: &ExtensionOffer) -> Result<Box<dyn Extension>, String> {
+        let params = parse_params(response)?;
+
+        if self.server_no_context_takeover && !params.server_no_context_takeover {
+            return Err(String::from(
+                "Server did not agree to permessage-deflate server_no_context_takeover",
+            ));
+        }
+        if self.server_max_window_bits < 15
+            && params.server_max_window_bits.unwrap_or(15) > self.server_max_window_bits
+        {
+            return Err(String::from(
+                "Server did not agree to permessage-deflate server_max_window_bits",
+            ));
+        }
+        let client_bits = match params.client_max_window_bits {
+            None => self.client_max_window_bits,
+            Some(None) => {
+                return Err(String::from(
+                    "permessage-deflate client_max_window_bits must have a value in a response",
+                ))
+            }
+            Some(Some(bits)) => self.client_max_window_bits.min(bits),
+        };
+        let client_no_context_takeover =
+            self.client_no_context_takeover || params.client_no_context_takeover;
+
+        Ok(Box::new(DeflateStream {
+            deflater: Deflater::new(client_bits, !client_no_context_takeover),
+            inflater: Inflater::new(!params.server_no_context_takeover),
+            threshold: self.threshold,
+        }))
+    }
+}
+
+/// permessage-deflate as negotiated for one connection
+struct DeflateStream {
+    deflater: Deflater,
+    inflater: Inflater,
+    threshold: usize,
+}
+
+impl Extension for DeflateStream {
+    fn name(&self) -> &str {
+        "permessage-deflate"
+    }
+
+    fn rsv_bits(&self) -> u8 {
+        RSV1
+    }
+
+    fn accept(&self, _offer: &ExtensionOffer) -> Option<(ExtensionOffer, Box<dyn Extension>)> {
+        None
+    }
+
+    fn accepted(&self, _response: &ExtensionOffer) -> Result<Box<dyn Extension>, String> {
+        Err(String::from("permessage-deflate is already negotiated"))
+    }
+
+    /// https://www.rfc-editor.org/rfc/rfc7692#section-7.2.1
+    fn outgoing(&mut self, message: &mut ExtensionMessage) -> std::io::Result<()> {
+        if message.data.len() < self.threshold {
+            return Ok(());
+        }
+        let mut compressed = self.deflater.compress(&message.data);
+        compressed.truncate(compressed.len() - TRAILER.len());
+        message.data = compressed;
+        message.rsv |= RSV1;
+        Ok(())
+    }
+
+    /// https://www.rfc-editor.org/rfc/rfc7692#section-7.2.2
+    fn incoming(&mut self, message: &mut ExtensionMessage) -> Result<(), ExtensionError> {
+        if message.rsv & RSV1 == 0 {
+            return Ok(());
+        }
+        message.data.extend_from_slice(&TRAILER);
+        message.data = self
+            .inflater
+            .decompress(&message.data, MAX_MESSAGE_LEN)
+            .map_err(|e| ExtensionError {
+                code: match e {
+                    InflateError::TooLarge => 1009,
+                    _ => 1007,
+                },
+                reason: e.to_string(),
+            })?;
+        message.rsv &= !RSV1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn offer(header: &str) -> ExtensionOffer {
+        parse_extensions(header).unwrap().remove(0)
+    }
+
+    fn response(config: &PerMessageDeflate, header: &str) -> Option<String> {
+        config
+            .accept(&offer(header))
+            .map(|(response, _)| response.to_string())
+    }
+
+    fn message(data: &[u8]) -> ExtensionMessage {
+        ExtensionMessage {
+            text: true,
+            rsv: 0,
+            data: data.to_vec(),
+        }
+    }
+
+    /// Negotiate between a client and server with these settings, returning the server's response
+    /// and each side's chain
+    fn connect(
+        client: PerMessageDeflate,
+        server: PerMessageDeflate,
+    ) -> (String, ExtensionChain, ExtensionChain) {
+        let offers = parse_extensions(&client.offer().to_string()).unwrap();
+        let (responses, server_chain) = negotiate(&offers, &[Arc::new(server)]);
+        let header = responses
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let client_chain =
+            confirm(&parse_extensions(&header).unwrap(), &[Arc::new(client)]).unwrap();
+        (header, client_chain, server_chain)
+    }
+
+    /// Send `data` from one chain to the other, returning how many bytes went over the wire
+    fn send(from: &mut ExtensionChain, to: &mut ExtensionChain, data: &[u8]) -> usize {
+        let mut sent = message(data);
+        from.outgoing(&mut sent).unwrap();
+        let len = sent.data.len();
+        to.incoming(&mut sent).unwrap();
+        assert_eq!(sent.data, data);
+        assert_eq!(sent.rsv, 0);
+        len
+    }
+
+    #[test]
+    fn browser_offers() {
+        let config = PerMessageDeflate::default();
+        // Chrome
+        assert_eq!(
+            response(&config, "permessage-deflate; client_max_window_bits"),
+            Some(String::from("permessage-deflate"))
+        );
+        // Firefox and Safari
+        assert_eq!(
+            response(&config, "permessage-deflate"),
+            Some(String::from("permessage-deflate"))
+        );
+
+        let config = PerMessageDeflate {
+            client_max_window_bits: 10,
+            server_max_window_bits: 12,
+            server_no_context_takeover: true,
+            ..PerMessageDeflate::default()
+        };
+        assert_eq!(
+            response(&config, "permessage-deflate; client_max_window_bits").as_deref(),
+            Some("permessage-deflate; server_no_context_takeover; server_max_window_bits=12; client_max_window_bits=10")
+        );
+        // a client that didn't offer client_max_window_bits can't be asked for it
+        assert_eq!(
+            response(&config, "permessage-deflate").as_deref(),
+            Some("permessage-deflate; server_no_context_takeover; server_max_window_bits=12")
+        );
+    }
+
+    #[test]
+    fn server_params() {
+        let config = PerMessageDeflate::default();
+        assert_eq!(
+            response(
+                &config,
+                "permessage-deflate; server_no_context_takeover; client_no_context_takeover; server_max_window_bits=10; client_max_window_bits=9"
+            )
+            .as_deref(),
+            Some("permessage-deflate; server_no_context_takeover; client_no_context_takeover; server_max_window_bits=10; client_max_window_bits=9")
+        );
+        let config = PerMessageDeflate {
+            server_max_window_bits: 9,
+            ..PerMessageDeflate::default()
+        };
+        assert_eq!(
+            response(&config, "permessage-deflate; server_max_window_bits=12").as_deref(),
+            Some("permessage-deflate; server_max_window_bits=9")
+        );
+
+        for bad in [
+            "permessage-deflate; x-unknown",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; server_max_window_bits=7",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; server_max_window_bits=010",
+            "permessage-deflate; client_max_window_bits=abc",
+            "permessage-deflate; server_no_context_takeover=1",
+            "permessage-deflate; client_max_window_bits; client_max_window_bits",
+        ] {
+            assert_eq!(response(&config, bad), None, "{}", bad);
+        }
+
+        // a declined offer falls back to the client's next one
+        let offers = parse_extensions(
+            "permessage-deflate; server_max_window_bits=7, permessage-deflate; client_max_window_bits",
+        )
+        .unwrap();
+        let (responses, chain) = negotiate(&offers, &[Arc::new(PerMessageDeflate::default())]);
+        assert_eq!(responses, vec![ExtensionOffer::new("permessage-deflate")]);
+        assert_eq!(chain.rsv_bits(), RSV1);
+    }
+
+    #[test]
+    fn client_params() {
+        assert_eq!(
+            PerMessageDeflate::default().offer().to_string(),
+            "permessage-deflate; client_max_window_bits"
+        );
+        let config = PerMessageDeflate {
+            server_max_window_bits: 10,
+            client_max_window_bits: 11,
+            server_no_context_takeover: true,
+            client_no_context_takeover: true,
+            threshold: 0,
+        };
+        assert_eq!(
+            config.offer().to_string(),
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover; server_max_window_bits=10; client_max_window_bits=11"
+        );
+
+        let accepted = |header: &str| config.accepted(&offer(header)).map(|_| ()).err();
+        assert_eq!(
+            accepted("permessage-deflate; server_no_context_takeover; server_max_window_bits=9"),
+            None
+        );
+        assert_eq!(
+            accepted("permessage-deflate; server_max_window_bits=10"),
+            Some(String::from(
+                "Server did not agree to permessage-deflate server_no_context_takeover"
+            ))
+        );
+        assert_eq!(
+            accepted("permessage-deflate; server_no_context_takeover; server_max_window_bits=11"),
+            Some(String::from(
+                "Server did not agree to permessage-deflate server_max_window_bits"
+            ))
+        );
+        assert_eq!(
+            accepted("permessage-deflate; server_no_context_takeover"),
+            Some(String::from(
+                "Server did not agree to permessage-deflate server_max_window_bits"
+            ))
+        );
+        assert_eq!(
+            PerMessageDeflate::default()
+                .accepted(&offer("permessage-deflate; client_max_window_bits"))
+                .map(|_| ())
+                .err(),
+            Some(String::from(
+                "permessage-deflate client_max_window_bits must have a value in a response"
+            ))
+        );
+        assert_eq!(
+            PerMessageDeflate::default()
+                .accepted(&offer("permessage-deflate; x-unknown"))
+                .map(|_| ())
+                .err(),
+            Some(String::from(
+                "Unknown permessage-deflate parameter x-unknown"
+            ))
+        );
+    }
+
+    #[test]
+    fn compression() {
+        let (header, mut client, mut server) =
+            connect(PerMessageDeflate::default(), PerMessageDeflate::default());
+        assert_eq!(header, "permessage-deflate");
+
+        let json = br#"{"type":"update","payload":{"users":[{"id":1,"name":"alice"},{"id":2,"name":"bob"}],"status":"online"}}"#.repeat(20);
+        let first = send(&mut client, &mut server, &json);
+        assert!(first < json.len() / 5, "{} of {}", first, json.len());
+        // context takeover lets a repeat refer back to the first message
+        let second = send(&mut client, &mut server, &json);
+        assert!(second < first / 4, "{} then {}", second, first);
+        send(&mut server, &mut client, &json);
+        send(&mut server, &mut client, &json);
+
+        // short messages skip compression
+        let mut short = message(b"hi");
+        server.outgoing(&mut short).unwrap();
+        assert_eq!((short.rsv, short.data), (0, b"hi".to_vec()));
+    }
+
+    #[test]
+    fn no_context_takeover() {
+        let client = PerMessageDeflate {
+            client_no_context_takeover: true,
+            ..PerMessageDeflate::default()
+        };
+        let server = PerMessageDeflate {
+            server_no_context_takeover: true,
+            server_max_window_bits: 8,
+            client_max_window_bits: 9,
+            ..PerMessageDeflate::default()
+        };
+        let (header, mut client, mut server) = connect(client, server);
+        assert_eq!(header, "permessage-deflate; server_no_context_takeover; client_no_context_takeover; server_max_window_bits=8; client_max_window_bits=9");
+
+        let json = br#"{"id":1,"values":[1,2,3,4,5,6,7,8,9,10],"label":"chatty"}"#.repeat(10);
+        let first = send(&mut client, &mut server, &json);
+        assert_eq!(send(&mut client, &mut server, &json), first);
+        let first = send(&mut server, &mut client, &json);
+        assert_eq!(send(&mut server, &mut client, &json), first);
+    }
+
+    #[test]
+    fn rfc7692_messages() {
+        // https://www.rfc-editor.org/rfc/rfc7692#section-7.2.3
+        let mut stream = PerMessageDeflate::default()
+            .accepted(&offer("permessage-deflate"))
+            .unwrap();
+        for _ in 0..2 {
+            let mut hello = message(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]);
+            hello.rsv = RSV1;
+            stream.incoming(&mut hello).unwrap();
+            assert_eq!((hello.rsv, hello.data), (0, b"Hello".to_vec()));
+        }
+
+        let mut empty = message(b"");
+        stream.outgoing(&mut empty).unwrap();
+        assert_eq!(empty.data, b"");
+        let mut stream = PerMessageDeflate {
+            threshold: 0,
+            ..PerMessageDeflate::default()
+        }
+        .accepted(&offer("permessage-deflate"))
+        .unwrap();
+        stream.outgoing(&mut empty).unwrap();
+        assert_eq!((empty.rsv, empty.data), (RSV1, vec![0x00]));
+
+        let mut corrupt = message(&[0xff, 0xff, 0xff]);
+        corrupt.rsv = RSV1;
+        assert_eq!(stream.incoming(&mut corrupt).unwrap_err().code, 1007);
+    }
+}
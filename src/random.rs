@@ -0,0 +1,202 @@
+use std::{
+    fs::File,
+    io::Read,
+    sync::{Arc, Mutex},
+};
+
+/// Where the random bytes for handshake nonces, masking keys and Ping payloads come from
+pub trait RandomSource: Send {
+    fn fill(&mut self, buf: &mut [u8]);
+}
+
+impl<R: RandomSource + ?Sized> RandomSource for Box<R> {
+    fn fill(&mut self, buf: &mut [u8]) {
+        (**self).fill(buf)
+    }
+}
+
+impl<R: RandomSource + ?Sized> RandomSource for Arc<Mutex<R>> {
+    fn fill(&mut self, buf: &mut [u8]) {
+        self.lock().unwrap_or_else(|e| e.into_inner()).fill(buf)
+    }
+}
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+/// https://www.rfc-editor.org/rfc/rfc8439#section-2.1
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// https://www.rfc-editor.org/rfc/rfc8439#section-2.3
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: [u32; 3]) -> [u8; 64] {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&CONSTANTS);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(&nonce);
+
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut block = [0u8; 64];
+    for (bytes, (word, initial)) in block.chunks_exact_mut(4).zip(state.iter().zip(initial)) {
+        bytes.copy_from_slice(&word.wrapping_add(initial).to_le_bytes());
+    }
+    block
+}
+
+/// The ChaCha20 keystream for a 32 byte seed, with a 64 bit block counter and no nonce. The same
+/// seed always gives the same bytes, so a hand picked one makes keys and masks reproducible.
+pub struct ChaCha20Rng {
+    key: [u32; 8],
+    counter: u64,
+    block: [u8; 64],
+    /// How much of `block` has been handed out already
+    used: usize,
+}
+
+impl ChaCha20Rng {
+    pub fn from_seed(seed: [u8; 32]) -> ChaCha20Rng {
+        let mut key = [0u32; 8];
+        for (word, bytes) in key.iter_mut().zip(seed.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        ChaCha20Rng {
+            key,
+            counter: 0,
+            block: [0; 64],
+            used: 64,
+        }
+    }
+
+    /// Seeded from the operating system's randomness
+    pub fn from_os() -> std::io::Result<ChaCha20Rng> {
+        let mut seed = [0u8; 32];
+        File::open("/dev/urandom")?.read_exact(&mut seed)?;
+        Ok(ChaCha20Rng::from_seed(seed))
+    }
+}
+
+impl RandomSource for ChaCha20Rng {
+    fn fill(&mut self, buf: &mut [u8]) {
+        for b in buf {
+            if self.used == self.block.len() {
+                let nonce = [(self.counter >> 32) as u32, 0, 0];
+                self.block = chacha20_block(&self.key, self.counter as u32, nonce);
+                self.counter += 1;
+                self.used = 0;
+            }
+            *b = self.block[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+/// The default source, one `ChaCha20Rng` for the whole process that's seeded from `/dev/urandom`
+/// the first time it's used
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemRandom;
+
+impl RandomSource for SystemRandom {
+    fn fill(&mut self, buf: &mut [u8]) {
+        static GENERATOR: Mutex<Option<ChaCha20Rng>> = Mutex::new(None);
+        GENERATOR
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(|| {
+                ChaCha20Rng::from_os().expect("seeding random source from /dev/urandom")
+            })
+            .fill(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn rfc8439_block() {
+        // https://www.rfc-editor.org/rfc/rfc8439#section-2.3.2
+        let seed: Vec<u8> = (0..32).collect();
+        let key = ChaCha20Rng::from_seed(seed.try_into().unwrap()).key;
+        let block = chacha20_block(&key, 1, [0x09000000, 0x4a000000, 0]);
+        assert_eq!(
+            hex(&block),
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e\
+             d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e"
+        );
+    }
+
+    #[test]
+    fn keystream() {
+        // https://www.rfc-editor.org/rfc/rfc8439#appendix-A.1 test vectors 1 and 2, which are the
+        // first two blocks for an all zero key
+        let mut random = ChaCha20Rng::from_seed([0; 32]);
+        let mut bytes = [0u8; 128];
+        random.fill(&mut bytes);
+        assert_eq!(
+            hex(&bytes),
+            "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+             da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586\
+             9f07e7be5551387a98ba977c732d080dcb0f29a048e3656912c6533e32ee7aed\
+             29b721769ce64e43d57133b074d839d531ed1f28510afb45ace10a1f4b794d6f"
+        );
+    }
+
+    #[test]
+    fn seeded_is_reproducible() {
+        let mut whole = [0u8; 100];
+        ChaCha20Rng::from_seed([7; 32]).fill(&mut whole);
+
+        // however the bytes are asked for
+        let mut random = ChaCha20Rng::from_seed([7; 32]);
+        let mut pieces = [0u8; 100];
+        let (first, rest) = pieces.split_at_mut(30);
+        random.fill(first);
+        random.fill(rest);
+        assert_eq!(whole, pieces);
+
+        let mut other = [0u8; 100];
+        ChaCha20Rng::from_seed([8; 32]).fill(&mut other);
+        assert_ne!(whole, other);
+
+        // shared through a mutex it picks up where it left off
+        let mut shared: Arc<Mutex<Box<dyn RandomSource>>> =
+            Arc::new(Mutex::new(Box::new(ChaCha20Rng::from_seed([7; 32]))));
+        let mut via_shared = [0u8; 100];
+        shared.fill(&mut via_shared[..50]);
+        shared.clone().fill(&mut via_shared[50..]);
+        assert_eq!(whole, via_shared);
+    }
+
+    #[test]
+    fn system() {
+        let (mut a, mut b) = ([0u8; 32], [0u8; 32]);
+        SystemRandom.fill(&mut a);
+        SystemRandom.fill(&mut b);
+        assert_ne!(a, b);
+        assert_ne!(a, [0; 32]);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::handler::*;
+use crate::http::*;
+use std::{collections::HashMap, sync::Arc};
+
+/// One piece of a route's path between slashes
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// Has to match exactly
+    Literal(String),
+    /// `:name`, matches any single segment and captures it as `name`
+    Param(String),
+    /// `*`, matches whatever is left of the path, including nothing
+    Rest,
+}
+
+struct Route {
+    segments: Vec<Segment>,
+    handler: Arc<dyn Handler>,
+}
+
+/// Picks the `Handler` for a connection from the path it asked for. Patterns are made of literal
+/// segments, `:name` segments that capture into `HandshakeRequest::params`, and a trailing `*`
+/// that matches anything below it. Routes are tried in the order they were added.
+/// e.g. `Router::new().route("/chat", Chat).route("/rooms/:id", Room)`
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: Vec::new() }
+    }
+
+    pub fn route<H: Handler + 'static>(mut self, pattern: &str, handler: H) -> Router {
+        let segments = split_path(pattern)
+            .map(|segment| match segment {
+                "*" => Segment::Rest,
+                _ => match segment.strip_prefix(':') {
+                    Some(name) => Segment::Param(name.to_string()),
+                    None => Segment::Literal(segment.to_string()),
+                },
+            })
+            .collect();
+        self.routes.push(Route {
+            segments,
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    /// The handler for `path` along with the parameters its pattern captured, or `None` if no
+    /// route matches
+    pub(crate) fn find(&self, path: &str) -> Option<(Arc<dyn Handler>, HashMap<String, String>)> {
+        self.routes.iter().find_map(|route| {
+            route
+                .matches(path)
+                .map(|params| (route.handler.clone(), params))
+        })
+    }
+}
+
+impl Route {
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let mut parts = split_path(path);
+        for segment in &self.segments {
+            match segment {
+                Segment::Rest => return Some(params),
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let part = parts.next()?;
+                    if part.is_empty() {
+                        return None;
+                    }
+                    params.insert(name.clone(), percent_decode(part));
+                }
+            }
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+/// Any single handler is a router that sends every path to it
+impl<H: Handler + 'static> From<H> for Router {
+    fn from(handler: H) -> Router {
+        Router::new().route("/*", handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::*;
+    use crate::server::*;
+
+    fn named(name: &'static str) -> impl Handler {
+        move |handle: &mut ServerHandle, _: Message| _ = handle.send_text(name)
+    }
+
+    fn find_params(router: &Router, path: &str) -> Option<HashMap<String, String>> {
+        router.find(path).map(|(_, params)| params)
+    }
+
+    #[test]
+    fn literal_routes() {
+        let router = Router::new()
+            .route("/", named("root"))
+            .route("/chat", named("chat"));
+        assert_eq!(find_params(&router, "/"), Some(HashMap::new()));
+        assert_eq!(find_params(&router, "/chat"), Some(HashMap::new()));
+        assert_eq!(find_params(&router, "/chat/"), None);
+        assert_eq!(find_params(&router, "/chats"), None);
+        assert_eq!(find_params(&router, "/other"), None);
+    }
+
+    #[test]
+    fn params() {
+        let router = Router::new().route("/rooms/:id/users/:user", named("user"));
+        let params = find_params(&router, "/rooms/42/users/j%C3%A9r%C3%B4me").unwrap();
+        assert_eq!(params.get("id").map(|s| s.as_str()), Some("42"));
+        assert_eq!(params.get("user").map(|s| s.as_str()), Some("jérôme"));
+
+        assert_eq!(find_params(&router, "/rooms/42/users"), None);
+        assert_eq!(find_params(&router, "/rooms//users/x"), None);
+        assert_eq!(find_params(&router, "/rooms/42/users/x/y"), None);
+    }
+
+    #[test]
+    fn rest() {
+        let router = Router::new().route("/static/*", named("static"));
+        assert!(find_params(&router, "/static").is_some());
+        assert!(find_params(&router, "/static/a/b/c").is_some());
+        assert!(find_params(&router, "/dynamic/a").is_none());
+
+        let router = Router::from(named("everything"));
+        assert!(find_params(&router, "/").is_some());
+        assert!(find_params(&router, "/anything/at/all").is_some());
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let router = Router::new()
+            .route("/rooms/lobby", named("lobby"))
+            .route("/rooms/:id", named("room"));
+        assert_eq!(find_params(&router, "/rooms/lobby"), Some(HashMap::new()));
+        assert_eq!(
+            find_params(&router, "/rooms/7").unwrap().get("id"),
+            Some(&String::from("7"))
+        );
+    }
+}
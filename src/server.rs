@@ -1,32 +1,110 @@
+use crate::auth::*;
+use crate::base64;
+use crate::connection::*;
+use crate::extension::*;
+use crate::handler::*;
+use crate::heartbeat::*;
+use crate::host::*;
+use crate::http::*;
 use crate::log::*;
+use crate::message::*;
+use crate::origin::*;
+use crate::router::*;
+use crate::sha1::*;
 use crate::util::*;
-use base64ct::{Base64, Encoding};
-use sha1::{Digest, Sha1};
 use std::{
+    any::Any,
     collections::HashMap,
-    io::{BufRead, BufReader, Write},
-    net::{Shutdown, TcpListener, TcpStream},
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
 };
 
-pub(crate) struct WebSocketServer {
+pub struct WebSocketServer {
     _listener: TcpListener,
+    /// How often to Ping each client once connected, `None` to never
+    pub heartbeat_interval: Option<Duration>,
+    /// How long a Ping can go unanswered before the client is considered dead
+    pub heartbeat_timeout: Duration,
+    /// Subprotocols this server speaks, most preferred first. The first one the client also
+    /// offered is picked, https://www.rfc-editor.org/rfc/rfc6455#section-4.2.2
+    pub protocols: Vec<String>,
+    /// Extensions this server supports, accepted whenever a client offers them in a way they're
+    /// willing to take
+    pub extensions: Vec<Arc<dyn Extension>>,
+    /// Which web pages may connect, going by the Origin header. Every origin by default.
+    pub origins: OriginPolicy,
+    /// Which names the server answers to, going by the Host header. Any host by default.
+    pub hosts: HostPolicy,
+    authorize: Option<Box<Authorize>>,
 }
 
-struct ServerHandle<S: Stream> {
-    stream: S,
+/// One client's connection, as seen by a `Handler`
+pub struct ServerHandle<S: Stream = TcpStream> {
+    connection: Connection<S>,
+    /// Filled in once the handshake has been validated
+    request: HandshakeRequest,
+    user_data: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+impl Clone for ServerHandle<TcpStream> {
+    fn clone(&self) -> Self {
+        Self {
+            connection: self.connection.clone(),
+            request: self.request.clone(),
+            user_data: self.user_data.clone(),
+        }
+    }
 }
 
 impl WebSocketServer {
-    pub(crate) fn create(bind_addr: &str) -> std::io::Result<WebSocketServer> {
+    pub fn create(bind_addr: &str) -> std::io::Result<WebSocketServer> {
         let _listener = TcpListener::bind(bind_addr)?;
-        Ok(WebSocketServer { _listener })
+        Ok(WebSocketServer {
+            _listener,
+            heartbeat_interval: Some(DEFAULT_HEARTBEAT_INTERVAL),
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            protocols: Vec::new(),
+            extensions: Vec::new(),
+            origins: OriginPolicy::default(),
+            hosts: HostPolicy::default(),
+            authorize: None,
+        })
+    }
+
+    /// Decide whether each handshake that passed validation and found a route may connect, e.g.
+    /// by checking a bearer token, session cookie or signed query parameter. Runs before the
+    /// `Handler` sees the handshake. A `Rejection` is sent back as is, `AcceptOptions` can attach
+    /// data to the connection.
+    pub fn authorize<F>(&mut self, authorize: F)
+    where
+        F: Fn(&HandshakeRequest) -> Result<AcceptOptions, Rejection> + Send + Sync + 'static,
+    {
+        self.authorize = Some(Box::new(authorize));
+    }
+
+    fn check_authorization(&self, request: &HandshakeRequest) -> Result<AcceptOptions, Rejection> {
+        match &self.authorize {
+            Some(authorize) => authorize(request),
+            None => Ok(AcceptOptions::default()),
+        }
     }
 
-    pub(crate) fn listen(self) -> std::io::Result<()> {
-        for stream in self._listener.incoming().flatten() {
-            std::thread::spawn(|| {
-                let mut handle = ServerHandle::<TcpStream> { stream };
-                handle.handle_client()
+    /// Accept clients forever, each on its own thread, handing their connections to the handler
+    /// `router` picks for the requested path. A single `Handler` takes every path.
+    pub fn listen(self, router: impl Into<Router>) -> std::io::Result<()> {
+        let router = Arc::new(router.into());
+        let server = Arc::new(self);
+        for stream in server._listener.incoming().flatten() {
+            let (server, router) = (server.clone(), router.clone());
+            std::thread::spawn(move || {
+                let mut handle = ServerHandle::<TcpStream> {
+                    connection: Connection::new(stream, Role::Server),
+                    request: HandshakeRequest::default(),
+                    user_data: None,
+                };
+                handle.handle_client(&server, &router)
             });
         }
         Ok(())
@@ -34,211 +112,373 @@ impl WebSocketServer {
 }
 
 impl ServerHandle<TcpStream> {
-    pub(crate) fn handle_client(&mut self) -> std::io::Result<()> {
+    fn handle_client(&mut self, server: &WebSocketServer, router: &Router) -> std::io::Result<()> {
         self.log(String::from("New Client Connected"), LogLevel::Info);
-        let mut reader = BufReader::new(self.stream.try_clone()?);
-        let recv: Vec<u8> = reader.fill_buf()?.to_vec();
-        reader.consume(recv.len());
-
         // need to first handle the handshake, then start processing data
-        let handshake = String::from_utf8(recv).map_err(|_| {
-            self.stream
-                .shutdown(Shutdown::Both)
-                .expect("Shutdown succeeded");
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Failed to parse handshake as utf8",
-            )
-        })?;
-
-        match self.validate_handshake(
-            handshake,
-            self.stream
-                .local_addr()
-                .expect("local address found")
-                .to_string(),
-        ) {
-            // TODO: handle other HTTP protocol values, Sec-WebSocket-Protocol,
-            // Sec-WebSocket-Extensions, and any additional headers
-            Ok(key) => {
-                let response = format!(
-                    "HTTP/1.1 101 Switching Protocols\n\
-                    Upgrade: websocket\n\
-                    Connection: Upgrade\n\
-                    Sec-WebSocket-Accept: {key}"
+        let mut parser = HeadParser::new(MAX_HEAD_LEN);
+        let mut read_buf = [0u8; 4096];
+        let (head, leftover) = loop {
+            let len = self.connection.get_mut().read(&mut read_buf)?;
+            if len == 0 {
+                self.log(
+                    String::from("Client disconnected during handshake"),
+                    LogLevel::Warning,
                 );
-                self.stream.write_all(response.as_bytes())?;
-            }
-            Err(msg) => {
-                self.log(format!("Handshake failed - {}", msg), LogLevel::Warning);
-                let response = format!("HTTP/1.1 400 Bad Request\r\n\r\n{msg}");
-                self.stream.write_all(response.as_bytes())?;
-                self.stream
-                    .shutdown(Shutdown::Both)
-                    .expect("Shutdown failed");
                 return Ok(());
             }
+            match parser.feed(&read_buf[..len]) {
+                Ok(Some(parsed)) => break parsed,
+                Ok(None) => {}
+                Err(msg) => return self.reject_handshake(Response::bad_request(&msg)),
+            }
+        };
+
+        let (handler, protocol, extensions) = match self.validate_handshake(head, &server.hosts) {
+            Ok((mut request, key)) => {
+                if let Err(reason) = server
+                    .origins
+                    .check(request.header("origin"), request.header("host"))
+                {
+                    return self.reject_handshake(Response::new(403).with_body(&reason));
+                }
+                let Some((handler, params)) = router.find(&request.path) else {
+                    let response =
+                        Response::new(404).with_body(&format!("No route for {}", request.path));
+                    return self.reject_handshake(response);
+                };
+                request.params = params;
+                let options = match server.check_authorization(&request) {
+                    Ok(options) => options,
+                    Err(rejection) => {
+                        return match rejection.into_response() {
+                            Ok(response) => self.reject_handshake(response),
+                            Err(e) => self.server_error(e),
+                        }
+                    }
+                };
+                if !handler.on_handshake(&request) {
+                    let response = Response::new(403).with_body("Handshake rejected by handler");
+                    return self.reject_handshake(response);
+                }
+                let protocol = select_protocol(&request.protocols(), &server.protocols);
+                let offers = request.extensions().unwrap_or_else(|e| {
+                    self.log(
+                        format!("Ignoring Sec-WebSocket-Extensions - {}", e),
+                        LogLevel::Warning,
+                    );
+                    Vec::new()
+                });
+                let (accepted, extensions) = negotiate(&offers, &server.extensions);
+
+                let response = match switching_protocols(&key, protocol.as_deref(), &accepted)
+                    .with_custom_headers(&options.headers)
+                {
+                    Ok(response) => response,
+                    Err(e) => return self.server_error(e),
+                };
+                self.connection.get_mut().write_all(&response.encode())?;
+                self.request = request;
+                self.user_data = options.user_data;
+                (handler, protocol, extensions)
+            }
+            Err(response) => return self.reject_handshake(response),
         };
 
         self.log(
             String::from("Handshake complete, websocket established."),
             LogLevel::Info,
         );
+        // the client may not have waited for our 101 before sending its first frames
+        self.connection.unread(leftover);
+        self.connection.open(protocol, extensions);
+        if let Some(interval) = server.heartbeat_interval {
+            self.connection
+                .start_heartbeat(interval, server.heartbeat_timeout);
+        }
+
+        let request = self.request.clone();
+        handler.on_connect(self, &request);
+        self.serve(handler.as_ref());
+        Ok(())
+    }
+
+    /// Answer with a 500 when the application asked for a response that can't be sent, logging
+    /// why rather than telling the client
+    fn server_error(&mut self, reason: String) -> std::io::Result<()> {
+        self.log(
+            format!("Invalid handshake response - {}", reason),
+            LogLevel::Error,
+        );
+        self.reject_handshake(Response::new(500))
+    }
 
-        // echo back whatever we get from here on
+    /// Refuse the handshake with an error `response` and hang up
+    fn reject_handshake(&mut self, response: Response) -> std::io::Result<()> {
+        self.log(
+            format!("Handshake failed - {} {}", response.status, response.body),
+            LogLevel::Warning,
+        );
+        self.connection.get_mut().write_all(&response.encode())?;
+        // the client may already have hung up, which is no reason to fail
+        _ = Stream::shutdown(self.connection.get_ref());
+        Ok(())
+    }
+}
+
+/// The 101 that completes the handshake, https://www.rfc-editor.org/rfc/rfc6455#section-4.2.2
+fn switching_protocols(
+    accept_key: &str,
+    protocol: Option<&str>,
+    extensions: &[ExtensionOffer],
+) -> Response {
+    let mut response = Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key);
+    if let Some(protocol) = protocol {
+        response = response.with_header("Sec-WebSocket-Protocol", protocol);
+    }
+    if !extensions.is_empty() {
+        let extensions: Vec<String> = extensions.iter().map(|e| e.to_string()).collect();
+        response = response.with_header("Sec-WebSocket-Extensions", &extensions.join(", "));
+    }
+    response
+}
+
+/// 426 Upgrade Required, listing the only version rhubarb speaks
+/// https://www.rfc-editor.org/rfc/rfc6455#section-4.4
+fn unsupported_version() -> Response {
+    Response::new(426)
+        .with_header("Sec-WebSocket-Version", "13")
+        .with_body("Requested Sec-WebSocket-Version was not '13'")
+}
+
+/// The first of the server's `supported` subprotocols that the client `offered`. The client's own
+/// order is only a hint, so the server's preference wins.
+fn select_protocol(offered: &[&str], supported: &[String]) -> Option<String> {
+    supported
+        .iter()
+        .find(|protocol| offered.contains(&protocol.as_str()))
+        .cloned()
+}
+
+impl<S: Stream> ServerHandle<S> {
+    pub fn connection(&self) -> &Connection<S> {
+        &self.connection
+    }
+
+    pub fn connection_mut(&mut self) -> &mut Connection<S> {
+        &mut self.connection
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.connection.peer_addr()
+    }
+
+    /// The handshake this connection was opened with, including the path parameters its route
+    /// captured
+    pub fn request(&self) -> &HandshakeRequest {
+        &self.request
+    }
+
+    /// Data attached to this connection by `WebSocketServer::authorize` or `set_user_data`, if
+    /// there is some and it's a `T`
+    pub fn user_data<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.user_data.as_ref()?.downcast_ref()
+    }
+
+    /// Attach data to this connection, replacing any there was. Clones of the handle made before
+    /// keep what they had.
+    pub fn set_user_data<T: Any + Send + Sync>(&mut self, data: T) {
+        self.user_data = Some(Arc::new(data));
+    }
+}
+
+impl<S: Stream + Write> ServerHandle<S> {
+    pub fn send_text(&mut self, text: &str) -> std::io::Result<()> {
+        self.connection.send_text(text)
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.connection.send_binary(data)
+    }
+
+    pub fn close(&mut self, code: u16, reason: &str) -> std::io::Result<()> {
+        self.connection.close(code, reason)
+    }
+}
+
+impl<S: Stream + Read + Write> ServerHandle<S> {
+    /// Hand everything received on an open connection to `handler` until it closes
+    fn serve(&mut self, handler: &dyn Handler<S>) {
         loop {
-            let recv: Vec<u8> = reader.fill_buf()?.to_vec();
-            reader.consume(recv.len());
-            let message = String::from_utf8(recv).unwrap();
-            if !message.is_empty() {
-                print!(
-                    "{} - {}",
-                    self.stream.peer_addr().expect("peer address found"),
-                    message
-                );
-                _ = self.stream.write_all(message.as_bytes());
+            match self.connection.recv() {
+                Ok(Some(Message::Ping(data))) => handler.on_ping(self, &data),
+                // Pongs and the closing handshake are the connection's business
+                Ok(Some(Message::Pong(_) | Message::Close(_))) => {}
+                Ok(Some(message)) => handler.on_message(self, message),
+                Ok(None) => break,
+                Err(e) => {
+                    self.log(format!("Connection failed - {}", e), LogLevel::Warning);
+                    handler.on_error(self, &e);
+                    break;
+                }
             }
         }
+        let close = self.connection.close_frame();
+        if let Some(close) = &close {
+            self.log(format!("Connection closed - {}", close), LogLevel::Info);
+        }
+        handler.on_close(self, close);
     }
 }
 
 impl<S: Stream> ServerHandle<S> {
-    /// Returns a result with either a valid value for Sec-WebSocket-Accept, or a string to be used
-    /// in a 400 bad request
+    /// Returns a result with either the request and a valid value for Sec-WebSocket-Accept, or the
+    /// error response to refuse it with
     fn validate_handshake(
         &self,
-        client_handshake: String,
-        hostname: String,
-    ) -> Result<String, String> {
+        mut head: MessageHead,
+        hosts: &HostPolicy,
+    ) -> Result<(HandshakeRequest, String), Response> {
         self.log(
-            format!("Validating client handshake\n{}", client_handshake),
+            format!("Validating client handshake\n{}", head),
             LogLevel::Debug,
         );
-        let mut components = client_handshake.trim().split('\n');
-        // pop the method + path + http version
-        let http_request = match components.next() {
-            Some(r) => r,
-            None => return Err(String::from("Handshake is not a valid HTTP request")),
-        };
+        // validated here, then handed to the application's `Handler` as part of the request
+        let headers = HeaderMap::from(std::mem::take(&mut head.headers));
+        let (method, uri, version) = head.request_line().map_err(|e| Response::bad_request(&e))?;
 
         // validation 1 - must be a GET request, with a valid Request-URI with HTTP/1.1 or higher
-        let mut request_components = http_request.split_whitespace();
-
-        let mut err = String::from("Handshake is not a GET Request");
-        match request_components.next() {
-            Some("GET") => {}
-            Some(_) => return Err(err),
-            None => return Err(err),
+        if method != "GET" {
+            return Err(Response::bad_request("Handshake is not a GET Request"));
         }
 
-        // TODO: not validating the URI yet: https://www.rfc-editor.org/rfc/rfc6455#section-3
-        err = String::from("Handshake contains invalid URI resource");
-        if request_components.next().is_none() {
-            return Err(err);
-        }
+        // only the path and query of a resource name, https://www.rfc-editor.org/rfc/rfc6455#section-3
+        let (path, query) = match uri {
+            uri if uri.starts_with('/') && !uri.contains('#') => split_request_uri(uri),
+            _ => {
+                return Err(Response::bad_request(
+                    "Handshake contains invalid URI resource",
+                ))
+            }
+        };
 
-        err =
-            String::from("Handshake is using an invalid HTTP version, must be HTTP/1.1 or higher");
-        match request_components.next() {
-            Some(http) => {
-                let c = http.split_once('/');
-                match c {
-                    Some(("HTTP", "1.1")) | Some(("HTTP", "2")) | Some(("HTTP", "3")) => {}
-                    Some(_) => return Err(err),
-                    None => return Err(err),
-                };
+        match version.split_once('/') {
+            Some(("HTTP", "1.1")) | Some(("HTTP", "2")) | Some(("HTTP", "3")) => {}
+            _ => {
+                return Err(Response::bad_request(
+                    "Handshake is using an invalid HTTP version, must be HTTP/1.1 or higher",
+                ))
             }
-            None => return Err(err),
         };
 
-        // TODO: I'm just chucking the rest of the headers here, but I could return them as part
-        // of a tuple or struct or something, then pass back to a closure on the `handle_client`
-        // and `listen` funcs.
-        // e.g. the api is something like:
-        // WebSocketClient::create("...").listen(on_initial_conn: { request }, on_recv: { bytes })
-        // ergonomics wise I could also register those callbacks using their own funcs
-        // or both, both is good
-        let headers = components
-            .filter_map(|header| header.split_once(':'))
-            .map(|(header_name, val)| (header_name.trim().to_lowercase(), val.trim()))
-            .collect::<HashMap<_, _>>();
-
-        // validation 2 - must include a Host header matching server
-        match headers.get("host") {
-            Some(given_host) if *given_host.trim().to_string() == hostname => {}
-            Some(_) => return Err(String::from("Invalid hostname")),
-            None => return Err(String::from("Handshake missing Host header")),
+        // validation 2 - must include a Host header the server answers to
+        match headers
+            .get_unique("Host")
+            .map_err(|e| Response::bad_request(&e))?
+        {
+            Some(given_host) if hosts.accepts(given_host) => {}
+            Some(_) => return Err(Response::bad_request("Invalid hostname")),
+            None => return Err(Response::bad_request("Handshake missing Host header")),
         };
 
-        // validation 3 - must include "upgrade: websocket" header
+        // validation 3 - must include "upgrade: websocket" header, possibly among other protocols
         match headers.get("upgrade") {
-            Some(ug) if ug.to_lowercase() == "websocket" => {}
-            Some(_) => return Err(String::from("Requested Upgrade was not 'websocket'")),
-            None => return Err(String::from("Handshake missing Upgrade header")),
+            Some(_) if headers.has_token("upgrade", "websocket") => {}
+            Some(_) => {
+                return Err(Response::bad_request(
+                    "Requested Upgrade was not 'websocket'",
+                ))
+            }
+            None => return Err(Response::bad_request("Handshake missing Upgrade header")),
         };
 
-        // validation 4 - must include "connection: upgrade" header
+        // validation 4 - must include "connection: upgrade" header, possibly among other options
+        // like "keep-alive, Upgrade"
         match headers.get("connection") {
-            Some(conn) if conn.to_lowercase() == "upgrade" => {}
-            Some(_) => return Err(String::from("Requested Connection was not 'upgrade'")),
-            None => return Err(String::from("Handshake missing Connection header")),
+            Some(_) if headers.has_token("connection", "upgrade") => {}
+            Some(_) => {
+                return Err(Response::bad_request(
+                    "Requested Connection was not 'upgrade'",
+                ))
+            }
+            None => return Err(Response::bad_request("Handshake missing Connection header")),
         };
 
         // validation 6 - "sec-websocket-version: 13". Process before key to avoid the hash if we can
         // NOTE: the RFC does allow for multiple version support: https://www.rfc-editor.org/rfc/rfc6455#section-4.4
         // but that is out of scope for this little toy (right now)
-        match headers.get("sec-websocket-version") {
-            Some(&"13") => {}
-            Some(_) => return Err(String::from("Requested Sec-WebSocket-Version was not '13'")),
-            None => {
-                return Err(String::from(
+        match headers.get_all("sec-websocket-version")[..] {
+            ["13"] => {}
+            [] => {
+                return Err(Response::bad_request(
                     "Handshake missing Sec-WebSocket-Version header",
                 ))
             }
+            // https://www.rfc-editor.org/rfc/rfc6455#section-4.4
+            _ => return Err(unsupported_version()),
         };
 
         // validation 5 - key
-        // This key must be exactly 24 characters (b64 on a 16 byte nonce), as per
+        // This key must be b64 of a 16 byte nonce, as per
         // https://www.rfc-editor.org/rfc/rfc6455#section-4.1
-        let mut key = match headers.get("sec-websocket-key") {
-            Some(h) => h.trim().to_string(),
-            None => return Err(String::from("Handshake missing Sec-WebSocket-Key header")),
+        let mut key = match headers
+            .get_unique("Sec-WebSocket-Key")
+            .map_err(|e| Response::bad_request(&e))?
+        {
+            Some(h) => h.to_string(),
+            None => {
+                return Err(Response::bad_request(
+                    "Handshake missing Sec-WebSocket-Key header",
+                ))
+            }
         };
 
-        if key.chars().count() != 24 {
-            return Err(String::from("Invalid Sec-WebSocket-Key"));
+        if base64::decode(&key).map(|nonce| nonce.len()) != Some(16) {
+            return Err(Response::bad_request("Invalid Sec-WebSocket-Key"));
         }
 
         // the magic UUID from https://www.rfc-editor.org/rfc/rfc6455#section-1.3
         key += "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-        // TODO: I would like to write a pure-rust version of this myself, but right now I'm cheating and
-        // just calling into rustcrypto
-        let hash = Sha1::digest(key.as_bytes());
-        let base64_hash = Base64::encode_string(&hash);
-        Ok(base64_hash)
+        let hash = sha1(key.as_bytes());
+        let base64_hash = base64::encode(&hash);
+        let request = HandshakeRequest {
+            method: method.to_string(),
+            path,
+            query,
+            params: HashMap::new(),
+            version: version.to_string(),
+            cookies: parse_cookies(&headers),
+            headers,
+            peer_addr: self.connection.peer_addr().ok(),
+        };
+        Ok((request, base64_hash))
     }
 
     fn log(&self, msg: String, level: LogLevel) {
-        // NOTE: this expect is half-reasonable since if we can't get a peer addr how are we
-        // connected, but it should probably be handled more gracefully
-        log(
-            format!(
-                "{} - {msg}",
-                self.stream.peer_addr().expect("peer address found")
-            ),
-            level,
-        );
+        self.connection.log(msg, level);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::{
+        io::Cursor,
+        net::{IpAddr, Ipv4Addr},
+        sync::Mutex,
+    };
 
     use super::*;
+    use crate::client::*;
+    use crate::close::*;
+    use crate::frame::*;
 
-    struct MockStream {}
+    struct MockStream {
+        incoming: Cursor<Vec<u8>>,
+        outgoing: Vec<u8>,
+    }
     impl Stream for MockStream {
         fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr> {
             Ok(std::net::SocketAddr::new(
@@ -246,33 +486,410 @@ mod tests {
                 4024,
             ))
         }
+
+        fn shutdown(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn set_read_timeout(&self, _dur: Option<Duration>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.outgoing.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
     }
 
     fn make_test_handle() -> ServerHandle<MockStream> {
+        make_open_handle(vec![])
+    }
+
+    /// A handle past the handshake, with `frames` from the client waiting to be read
+    fn make_open_handle(frames: Vec<WebSocketFrame>) -> ServerHandle<MockStream> {
+        let incoming = frames.into_iter().flat_map(|f| f.encode()).collect();
+        let mut connection = Connection::new(
+            MockStream {
+                incoming: Cursor::new(incoming),
+                outgoing: Vec::new(),
+            },
+            Role::Server,
+        );
+        connection.open(None, ExtensionChain::default());
         ServerHandle {
-            stream: MockStream {},
+            connection,
+            request: HandshakeRequest::default(),
+            user_data: None,
+        }
+    }
+
+    /// Parse a handshake written out as indented lines, the way the tests below lay them out
+    fn head(raw: &str) -> MessageHead {
+        let raw: String = raw
+            .lines()
+            .map(|line| format!("{}\r\n", line.trim()))
+            .collect();
+        HeadParser::new(MAX_HEAD_LEN)
+            .feed(format!("{raw}\r\n").as_bytes())
+            .unwrap()
+            .unwrap()
+            .0
+    }
+
+    fn client_frame(opcode: WebSocketOpCode, data: &[u8]) -> WebSocketFrame {
+        WebSocketFrame::new_bin(true, opcode, data.to_vec(), Some([1, 2, 3, 4]))
+    }
+
+    /// Writes down every callback it gets, and echoes messages back
+    #[derive(Default)]
+    struct RecordingHandler {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Handler<MockStream> for RecordingHandler {
+        fn on_message(&self, handle: &mut ServerHandle<MockStream>, message: Message) {
+            if let Message::Text(text) = &message {
+                handle.send_text(text).unwrap();
+            }
+            self.events.lock().unwrap().push(format!("{:?}", message));
+        }
+
+        fn on_ping(&self, _handle: &mut ServerHandle<MockStream>, data: &[u8]) {
+            self.events.lock().unwrap().push(format!("ping {:?}", data));
+        }
+
+        fn on_error(&self, _handle: &mut ServerHandle<MockStream>, _err: &std::io::Error) {
+            self.events.lock().unwrap().push(String::from("error"));
         }
+
+        fn on_close(&self, _handle: &mut ServerHandle<MockStream>, close: Option<CloseFrame>) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("close {}", close.unwrap()));
+        }
+    }
+
+    #[test]
+    fn handler_callbacks() {
+        let mut handle = make_open_handle(vec![
+            client_frame(WebSocketOpCode::Text, b"hi"),
+            client_frame(WebSocketOpCode::Ping, &[7]),
+            client_frame(WebSocketOpCode::Binary, &[1, 2]),
+            client_frame(
+                WebSocketOpCode::Close,
+                &CloseFrame::new(1000, "").unwrap().encode(),
+            ),
+        ]);
+        let handler = RecordingHandler::default();
+        handle.serve(&handler);
+
+        assert_eq!(
+            *handler.events.lock().unwrap(),
+            vec![
+                String::from("Text(\"hi\")"),
+                String::from("ping [7]"),
+                String::from("Binary([1, 2])"),
+                String::from("close 1000"),
+            ]
+        );
+        // the reply, the Pong, then the Close echo
+        let sent = FrameDecoder::new(Some(false))
+            .feed(&handle.connection.get_ref().outgoing)
+            .unwrap();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0].opcode, WebSocketOpCode::Text);
+        assert_eq!(sent[0].data, b"hi".to_vec());
+        assert_eq!(sent[1].opcode, WebSocketOpCode::Pong);
+        assert_eq!(sent[2].opcode, WebSocketOpCode::Close);
+    }
+
+    #[test]
+    fn handler_errors() {
+        // unmasked frames from a client fail the connection
+        let mut handle = make_open_handle(vec![WebSocketFrame::new_bin(
+            true,
+            WebSocketOpCode::Text,
+            b"hi".to_vec(),
+            None,
+        )]);
+        let handler = RecordingHandler::default();
+        handle.serve(&handler);
+        assert_eq!(
+            *handler.events.lock().unwrap(),
+            vec![
+                String::from("error"),
+                String::from("close 1006 frame masking is invalid for this endpoint")
+            ]
+        );
+    }
+
+    #[test]
+    fn closure_handler() {
+        let mut handle = make_open_handle(vec![client_frame(WebSocketOpCode::Text, b"hi")]);
+        let received = Mutex::new(Vec::new());
+        handle.serve(&|_: &mut ServerHandle<MockStream>, message: Message| {
+            received.lock().unwrap().push(message)
+        });
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![Message::Text(String::from("hi"))]
+        );
     }
 
     #[test]
     fn valid_handshake() {
         let server = make_test_handle();
 
-        assert_eq!(
-            server.validate_handshake(
-                String::from(
+        let (request, key) = server
+            .validate_handshake(
+                head(
                     "GET /ws HTTP/1.1
                     Host: 127.0.0.1:4024
                     Upgrade: websocket
                     Connection: Upgrade
                     Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
                     Sec-WebSocket-Protocol: rhubarb
-                    Sec-WebSocket-Version: 13"
+                    Sec-WebSocket-Version: 13",
                 ),
-                String::from("127.0.0.1:4024")
-            ),
-            Ok(String::from("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="))
+                &HostPolicy::new().allow("127.0.0.1:4024"),
+            )
+            .unwrap();
+        assert_eq!(key, String::from("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(request.path, "/ws");
+        assert_eq!(request.header("Sec-WebSocket-Protocol"), Some("rhubarb"));
+        assert_eq!(request.header("origin"), None);
+    }
+
+    #[test]
+    fn full_request() {
+        let server = make_test_handle();
+
+        let (request, _) = server
+            .validate_handshake(
+                head(
+                    "GET /chat?room=lobby HTTP/1.1
+                    Host: localhost
+                    Upgrade: websocket
+                    Connection: Upgrade
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
+                    Sec-WebSocket-Version: 13
+                    Sec-WebSocket-Protocol: chat, superchat
+                    Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits
+                    Sec-WebSocket-Extensions: x-reverse
+                    Cookie: session=abc123; theme=dark
+                    Authorization: Bearer t0k3n
+                    X-Forwarded-For: 10.0.0.1
+                    X-Forwarded-For: 10.0.0.2",
+                ),
+                &HostPolicy::new().allow("localhost"),
+            )
+            .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/chat");
+        assert_eq!(request.query["room"], "lobby");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.cookies["session"], "abc123");
+        assert_eq!(request.cookies["theme"], "dark");
+        assert_eq!(request.header("authorization"), Some("Bearer t0k3n"));
+        assert_eq!(
+            request.headers.get_all("x-forwarded-for"),
+            vec!["10.0.0.1", "10.0.0.2"]
+        );
+        assert_eq!(request.protocols(), vec!["chat", "superchat"]);
+        let extensions = request.extensions().unwrap();
+        assert_eq!(
+            extensions
+                .iter()
+                .map(|e| e.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["permessage-deflate", "x-reverse"]
+        );
+        assert_eq!(request.peer_addr, server.peer_addr().ok());
+    }
+
+    fn bearer_token(request: &HandshakeRequest) -> Result<AcceptOptions, Rejection> {
+        match request.header("authorization") {
+            Some("Bearer s3cr3t") => Ok(AcceptOptions::new()
+                .with_user_data(String::from("alice"))
+                .with_header("Set-Cookie", "session=abc123; HttpOnly")),
+            // a header value taken from the request without checking it
+            Some("Bearer inject") => {
+                Ok(AcceptOptions::new().with_header("X-User", "alice\r\nSet-Cookie: admin=1"))
+            }
+            Some(_) => Err(Rejection::forbidden().with_body("Invalid token")),
+            None => Err(Rejection::unauthorized()
+                .with_header("WWW-Authenticate", "Bearer")
+                .with_body("Missing token")),
+        }
+    }
+
+    #[test]
+    fn authorization() {
+        let mut server = WebSocketServer::create("127.0.0.1:0").unwrap();
+        let mut request = HandshakeRequest::default();
+        assert!(server.check_authorization(&request).is_ok());
+
+        server.authorize(bearer_token);
+        assert_eq!(
+            server.check_authorization(&request).err(),
+            Some(
+                Rejection::unauthorized()
+                    .with_header("WWW-Authenticate", "Bearer")
+                    .with_body("Missing token")
+            )
+        );
+        request.headers.append("Authorization", "Bearer guess");
+        assert_eq!(
+            server.check_authorization(&request).err(),
+            Some(Rejection::forbidden().with_body("Invalid token"))
+        );
+
+        let mut request = HandshakeRequest::default();
+        request.headers.append("Authorization", "Bearer s3cr3t");
+        let options = server.check_authorization(&request).ok().unwrap();
+        let mut handle = make_test_handle();
+        assert_eq!(handle.user_data::<String>(), None);
+        handle.user_data = options.user_data;
+        assert_eq!(handle.user_data::<String>().unwrap(), "alice");
+        assert_eq!(handle.user_data::<u64>(), None);
+        handle.set_user_data(7u64);
+        assert_eq!(handle.user_data::<u64>(), Some(&7));
+    }
+
+    #[test]
+    fn authorized_connection() {
+        struct Greeter;
+        impl Handler for Greeter {
+            fn on_connect(&self, handle: &mut ServerHandle, _request: &HandshakeRequest) {
+                let name = handle.user_data::<String>().cloned().unwrap_or_default();
+                handle.send_text(&format!("hello {name}")).unwrap();
+            }
+        }
+
+        let mut server = WebSocketServer::create("127.0.0.1:0").unwrap();
+        server.authorize(bearer_token);
+        let addr = server._listener.local_addr().unwrap();
+        std::thread::spawn(move || server.listen(Greeter));
+
+        let connect = |authorization: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let request = format!(
+                "GET /ws HTTP/1.1\r\n\
+                Host: {addr}\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                Sec-WebSocket-Version: 13\r\n\
+                {authorization}\r\n"
+            );
+            stream.write_all(request.as_bytes()).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut response = Vec::new();
+            let mut buf = [0u8; 1024];
+            // the response, then the greeting or the end of the stream
+            while !response.ends_with(b"hello alice") {
+                match stream.read(&mut buf).unwrap() {
+                    0 => break,
+                    len => response.extend_from_slice(&buf[..len]),
+                }
+            }
+            response
+        };
+
+        let refused = connect("");
+        assert!(refused.starts_with(b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\n"));
+        assert!(refused.ends_with(b"\r\n\r\nMissing token"));
+        assert!(connect("Authorization: Bearer guess\r\n").starts_with(b"HTTP/1.1 403 Forbidden"));
+
+        let accepted = connect("Authorization: Bearer s3cr3t\r\n");
+        assert!(accepted.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(String::from_utf8_lossy(&accepted)
+            .contains("\r\nSet-Cookie: session=abc123; HttpOnly\r\n\r\n"));
+
+        let injected = connect("Authorization: Bearer inject\r\n");
+        assert!(injected.starts_with(b"HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(!String::from_utf8_lossy(&injected).contains("admin"));
+        assert!(accepted.ends_with(b"\r\n\r\n\x81\x0bhello alice"));
+    }
+
+    #[test]
+    fn client_host_header() {
+        struct Silent;
+        impl Handler for Silent {}
+
+        let mut server = WebSocketServer::create("127.0.0.1:0").unwrap();
+        server.hosts = HostPolicy::new().allow("localhost");
+        let port = server._listener.local_addr().unwrap().port();
+        std::thread::spawn(move || server.listen(Silent));
+
+        let handshake = |addr: String| {
+            let mut client = WebSocketClient::create(&addr).unwrap();
+            client.heartbeat_interval = None;
+            client.perform_handshake(String::from("/"))
+        };
+        assert!(handshake(format!("localhost:{port}")).is_ok());
+        assert!(handshake(format!("127.0.0.1:{port}")).is_err());
+    }
+
+    #[test]
+    fn upgrade_response() {
+        let extensions = [ExtensionOffer::new("permessage-deflate")
+            .with_param("client_max_window_bits", Some("10"))];
+        assert_eq!(
+            String::from_utf8(
+                switching_protocols("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", Some("chat"), &extensions)
+                    .encode()
+            )
+            .unwrap(),
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+             Sec-WebSocket-Protocol: chat\r\n\
+             Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=10\r\n\
+             \r\n"
+        );
+        assert_eq!(
+            switching_protocols("key", None, &[]).encode(),
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Accept: key\r\n\r\n"
+                .to_vec()
         );
+
+        let response = String::from_utf8(unsupported_version().encode()).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\n")
+        );
+    }
+
+    #[test]
+    fn protocol_selection() {
+        let supported = vec![String::from("v2.chat"), String::from("chat")];
+        assert_eq!(
+            select_protocol(&["chat", "v2.chat"], &supported),
+            Some(String::from("v2.chat"))
+        );
+        assert_eq!(
+            select_protocol(&["chat"], &supported),
+            Some(String::from("chat"))
+        );
+        assert_eq!(select_protocol(&["other"], &supported), None);
+        assert_eq!(select_protocol(&[], &supported), None);
+        assert_eq!(select_protocol(&["chat"], &[]), None);
     }
 
     #[test]
@@ -280,40 +897,99 @@ mod tests {
         let server = make_test_handle();
 
         assert_eq!(
-            server.validate_handshake(String::from("POST /ws HTTP/1.1"), String::from("localhost")),
-            Err(String::from("Handshake is not a GET Request"))
+            server.validate_handshake(
+                head("POST /ws HTTP/1.1"),
+                &HostPolicy::new().allow("localhost")
+            ),
+            Err(Response::bad_request("Handshake is not a GET Request"))
         );
         assert_eq!(
-            server.validate_handshake(String::from("GET /ws PTTH/1.1"), String::from("localhost")),
-            Err(String::from(
+            server.validate_handshake(
+                head("GET /ws PTTH/1.1"),
+                &HostPolicy::new().allow("localhost")
+            ),
+            Err(Response::bad_request(
                 "Handshake is using an invalid HTTP version, must be HTTP/1.1 or higher"
             ))
         );
         assert_eq!(
-            server.validate_handshake(String::from("GET /ws HTTP/1.0"), String::from("localhost")),
-            Err(String::from(
+            server.validate_handshake(
+                head("GET /ws HTTP/1.0"),
+                &HostPolicy::new().allow("localhost")
+            ),
+            Err(Response::bad_request(
                 "Handshake is using an invalid HTTP version, must be HTTP/1.1 or higher"
             ))
         );
     }
 
+    #[test]
+    fn request_uri() {
+        let server = make_test_handle();
+
+        let (request, _) = server
+            .validate_handshake(
+                head(
+                    "GET /rooms/42?user=bob&token=a%2Bb HTTP/1.1
+                    Host: localhost
+                    Upgrade: websocket
+                    Connection: Upgrade
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
+                    Sec-WebSocket-Version: 13",
+                ),
+                &HostPolicy::new().allow("localhost"),
+            )
+            .unwrap();
+        assert_eq!(request.path, "/rooms/42");
+        assert_eq!(request.query["user"], "bob");
+        assert_eq!(request.query["token"], "a+b");
+
+        for uri in ["ws", "http://localhost/ws", "/ws#fragment"] {
+            assert_eq!(
+                server.validate_handshake(
+                    head(&format!("GET {} HTTP/1.1", uri)),
+                    &HostPolicy::new().allow("localhost")
+                ),
+                Err(Response::bad_request(
+                    "Handshake contains invalid URI resource"
+                )),
+                "{}",
+                uri
+            );
+        }
+    }
+
     #[test]
     fn bad_host_header() {
         let server = make_test_handle();
 
         assert_eq!(
-            server.validate_handshake(String::from("GET /ws HTTP/1.1"), String::from("localhost")),
-            Err(String::from("Handshake missing Host header"))
+            server.validate_handshake(
+                head("GET /ws HTTP/1.1"),
+                &HostPolicy::new().allow("localhost")
+            ),
+            Err(Response::bad_request("Handshake missing Host header"))
         );
         assert_eq!(
             server.validate_handshake(
-                String::from(
+                head(
                     "GET /ws HTTP/1.1
             Host: badhost"
                 ),
-                String::from("localhost")
+                &HostPolicy::new().allow("localhost")
             ),
-            Err(String::from("Invalid hostname"))
+            Err(Response::bad_request("Invalid hostname"))
+        );
+        // even a server that answers to any name wants a well formed one
+        assert_eq!(
+            server.validate_handshake(
+                head(
+                    "GET /ws HTTP/1.1
+            Host: bad host"
+                ),
+                &HostPolicy::any()
+            ),
+            Err(Response::bad_request("Invalid hostname"))
         );
     }
 
@@ -323,53 +999,204 @@ mod tests {
 
         assert_eq!(
             server.validate_handshake(
-                String::from(
+                head(
                     "GET /ws HTTP/1.1
                     Host: localhost"
                 ),
-                String::from("localhost")
+                &HostPolicy::new().allow("localhost")
             ),
-            Err(String::from("Handshake missing Upgrade header"))
+            Err(Response::bad_request("Handshake missing Upgrade header"))
         );
         assert_eq!(
             server.validate_handshake(
-                String::from(
+                head(
                     "GET /ws HTTP/1.1
                     Host: localhost
                     Upgrade: Not Websocket"
                 ),
-                String::from("localhost")
+                &HostPolicy::new().allow("localhost")
+            ),
+            Err(Response::bad_request(
+                "Requested Upgrade was not 'websocket'"
+            ))
+        );
+    }
+
+    #[test]
+    fn repeated_and_listed_headers() {
+        let server = make_test_handle();
+
+        let (request, _) = server
+            .validate_handshake(
+                head(
+                    "GET /ws HTTP/1.1
+                    Host: localhost
+                    Upgrade: websocket
+                    Connection: keep-alive, Upgrade
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
+                    Sec-WebSocket-Version: 13
+                    Sec-WebSocket-Protocol: chat
+                    Sec-WebSocket-Protocol: superchat",
+                ),
+                &HostPolicy::new().allow("localhost"),
+            )
+            .unwrap();
+        assert_eq!(request.protocols(), vec!["chat", "superchat"]);
+
+        assert_eq!(
+            server.validate_handshake(
+                head(
+                    "GET /ws HTTP/1.1
+                    Host: localhost
+                    Host: otherhost"
+                ),
+                &HostPolicy::new().allow("localhost")
             ),
-            Err(String::from("Requested Upgrade was not 'websocket'"))
+            Err(Response::bad_request(
+                "Handshake contains more than one Host header"
+            ))
+        );
+        assert_eq!(
+            server.validate_handshake(
+                head(
+                    "GET /ws HTTP/1.1
+                    Host: localhost
+                    Upgrade: websocket
+                    Connection: Upgrade
+                    Sec-WebSocket-Version: 13
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
+                    Sec-WebSocket-Key: AQIDBAUGBwgJCgsMDQ4PEA=="
+                ),
+                &HostPolicy::new().allow("localhost")
+            ),
+            Err(Response::bad_request(
+                "Handshake contains more than one Sec-WebSocket-Key header"
+            ))
+        );
+
+        // the options can be split over several lines too, and in any case
+        let (request, key) = server
+            .validate_handshake(
+                head(
+                    "GET /ws HTTP/1.1
+                    Host: localhost
+                    upgrade: h2c, WebSocket
+                    connection: keep-alive
+                    CONNECTION: upgrade
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
+                    Sec-WebSocket-Version: 13",
+                ),
+                &HostPolicy::new().allow("localhost"),
+            )
+            .unwrap();
+        assert_eq!(key, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(
+            request.headers.get_all("Connection"),
+            vec!["keep-alive", "upgrade"]
         );
     }
 
+    // Opening handshakes as the browsers sent them to a rhubarb server on localhost:4024
+    const CHROME_HANDSHAKE: &str = "GET /ws HTTP/1.1\r\n\
+        Host: localhost:4024\r\n\
+        Connection: Upgrade\r\n\
+        Pragma: no-cache\r\n\
+        Cache-Control: no-cache\r\n\
+        User-Agent: Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) \
+        Chrome/120.0.0.0 Safari/537.36\r\n\
+        Upgrade: websocket\r\n\
+        Origin: http://localhost:8080\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Accept-Encoding: gzip, deflate, br\r\n\
+        Accept-Language: en-US,en;q=0.9\r\n\
+        Sec-WebSocket-Key: x3JJHMbDL1EzLkh9GBhXDw==\r\n\
+        Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\
+        \r\n";
+    const FIREFOX_HANDSHAKE: &str = "GET /ws HTTP/1.1\r\n\
+        Host: localhost:4024\r\n\
+        User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0\r\n\
+        Accept: */*\r\n\
+        Accept-Language: en-US,en;q=0.5\r\n\
+        Accept-Encoding: gzip, deflate, br\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Origin: http://localhost:8080\r\n\
+        Sec-WebSocket-Extensions: permessage-deflate\r\n\
+        Sec-WebSocket-Key: Ozm6BEjWRykmgtXlX8ttFA==\r\n\
+        Connection: keep-alive, Upgrade\r\n\
+        Sec-Fetch-Dest: empty\r\n\
+        Sec-Fetch-Mode: websocket\r\n\
+        Sec-Fetch-Site: same-site\r\n\
+        Pragma: no-cache\r\n\
+        Cache-Control: no-cache\r\n\
+        Upgrade: websocket\r\n\
+        \r\n";
+    const SAFARI_HANDSHAKE: &str = "GET /ws HTTP/1.1\r\n\
+        Host: localhost:4024\r\n\
+        Origin: http://localhost:8080\r\n\
+        Pragma: no-cache\r\n\
+        Cache-Control: no-cache\r\n\
+        Sec-WebSocket-Key: pHbTNQcCO+c0rgkHWU3W3A==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Extensions: permessage-deflate\r\n\
+        User-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 \
+        (KHTML, like Gecko) Version/17.2 Safari/605.1.15\r\n\
+        Connection: Upgrade\r\n\
+        Accept-Encoding: gzip, deflate\r\n\
+        Accept-Language: en-GB,en;q=0.9\r\n\
+        \r\n";
+
+    #[test]
+    fn browser_handshakes() {
+        let server = make_test_handle();
+        for (raw, accept) in [
+            (CHROME_HANDSHAKE, "HSmrc0sMlYUkAGmm5OPpG2HaGWk="),
+            (FIREFOX_HANDSHAKE, "OvIEHlwQfCJ0mxX/1WK2SBo6Zwg="),
+            (SAFARI_HANDSHAKE, "G8fw3xS7M8WJBJseZIwF4J8EeLA="),
+        ] {
+            let (head, leftover) = HeadParser::new(MAX_HEAD_LEN)
+                .feed(raw.as_bytes())
+                .unwrap()
+                .unwrap();
+            assert!(leftover.is_empty());
+            let (request, key) = server
+                .validate_handshake(head, &HostPolicy::new().allow("localhost:4024"))
+                .unwrap();
+            assert_eq!(key, accept);
+            assert_eq!(request.header("Origin"), Some("http://localhost:8080"));
+            let offers = parse_extensions(request.header("sec-websocket-extensions").unwrap());
+            assert_eq!(offers.unwrap()[0].name, "permessage-deflate");
+        }
+    }
+
     #[test]
     fn bad_connection_header() {
         let server = make_test_handle();
 
         assert_eq!(
             server.validate_handshake(
-                String::from(
+                head(
                     "GET /ws HTTP/1.1
             Host: localhost
             Upgrade: Websocket"
                 ),
-                String::from("localhost")
+                &HostPolicy::new().allow("localhost")
             ),
-            Err(String::from("Handshake missing Connection header"))
+            Err(Response::bad_request("Handshake missing Connection header"))
         );
         assert_eq!(
             server.validate_handshake(
-                String::from(
+                head(
                     "GET /ws HTTP/1.1
             Host: localhost
             Upgrade: Websocket
             Connection: Not Upgrade"
                 ),
-                String::from("localhost")
+                &HostPolicy::new().allow("localhost")
             ),
-            Err(String::from("Requested Connection was not 'upgrade'"))
+            Err(Response::bad_request(
+                "Requested Connection was not 'upgrade'"
+            ))
         );
     }
 
@@ -379,30 +1206,30 @@ mod tests {
 
         assert_eq!(
             server.validate_handshake(
-                String::from(
+                head(
                     "GET /ws HTTP/1.1
                     Host: localhost
                     Upgrade: Websocket
                     Connection: Upgrade"
                 ),
-                String::from("localhost")
+                &HostPolicy::new().allow("localhost")
             ),
-            Err(String::from(
+            Err(Response::bad_request(
                 "Handshake missing Sec-WebSocket-Version header"
             ))
         );
         assert_eq!(
             server.validate_handshake(
-                String::from(
+                head(
                     "GET /ws HTTP/1.1
                     Host: localhost
                     Upgrade: Websocket
                     Connection: Upgrade
                     Sec-WebSocket-Version: 14"
                 ),
-                String::from("localhost")
+                &HostPolicy::new().allow("localhost")
             ),
-            Err(String::from("Requested Sec-WebSocket-Version was not '13'"))
+            Err(unsupported_version())
         );
     }
 
@@ -412,20 +1239,22 @@ mod tests {
 
         assert_eq!(
             server.validate_handshake(
-                String::from(
+                head(
                     "GET /ws HTTP/1.1
                     Host: localhost
                     Upgrade: Websocket
                     Connection: Upgrade
                     Sec-WebSocket-Version: 13"
                 ),
-                String::from("localhost")
+                &HostPolicy::new().allow("localhost")
             ),
-            Err(String::from("Handshake missing Sec-WebSocket-Key header"))
+            Err(Response::bad_request(
+                "Handshake missing Sec-WebSocket-Key header"
+            ))
         );
         assert_eq!(
             server.validate_handshake(
-                String::from(
+                head(
                     "GET /ws HTTP/1.1
                     Host: localhost
                     Upgrade: Websocket
@@ -433,9 +1262,9 @@ mod tests {
                     Sec-WebSocket-Version: 13
                     Sec-WebSocket-Key: foo"
                 ),
-                String::from("localhost")
+                &HostPolicy::new().allow("localhost")
             ),
-            Err(String::from("Invalid Sec-WebSocket-Key"))
+            Err(Response::bad_request("Invalid Sec-WebSocket-Key"))
         );
     }
 }
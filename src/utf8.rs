@@ -0,0 +1,116 @@
+/// Validates UTF-8 that arrives in pieces, as the fragments of a Text message do. A code point may
+/// be split across fragments, so up to 3 bytes of an unfinished sequence are carried over to the
+/// next call. Invalid input is reported as soon as it's seen rather than once the message is done.
+/// https://www.rfc-editor.org/rfc/rfc6455#section-8.1
+#[derive(Debug, Default)]
+pub(crate) struct Utf8Validator {
+    /// Leading bytes of a code point that hasn't been completed yet
+    pending: Vec<u8>,
+}
+
+impl Utf8Validator {
+    pub(crate) fn new() -> Utf8Validator {
+        Utf8Validator {
+            pending: Vec::new(),
+        }
+    }
+
+    /// Validate the next chunk of input. Returns `false` once the input so far can no longer be
+    /// the start of valid UTF-8.
+    pub(crate) fn feed(&mut self, mut bytes: &[u8]) -> bool {
+        // finish off the code point left over from last time, one byte at a time since we don't
+        // know how many it still needs
+        while !self.pending.is_empty() {
+            let Some((b, rest)) = bytes.split_first() else {
+                return true;
+            };
+            self.pending.push(*b);
+            bytes = rest;
+            match std::str::from_utf8(&self.pending) {
+                Ok(_) => self.pending.clear(),
+                Err(e) if e.error_len().is_some() => return false,
+                Err(_) => {}
+            }
+        }
+
+        match std::str::from_utf8(bytes) {
+            Ok(_) => true,
+            // error_len is None when the input just stops partway through a code point
+            Err(e) if e.error_len().is_none() => {
+                self.pending.extend_from_slice(&bytes[e.valid_up_to()..]);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the input ended on a code point boundary. Resets the validator for the next message.
+    pub(crate) fn finish(&mut self) -> bool {
+        let complete = self.pending.is_empty();
+        self.pending.clear();
+        complete
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_input() {
+        let mut validator = Utf8Validator::new();
+        assert!(validator.feed("κόσμε".as_bytes()));
+        assert!(validator.finish());
+        assert!(validator.feed(b""));
+        assert!(validator.finish());
+    }
+
+    #[test]
+    fn split_code_points() {
+        // every possible split of a mix of 1, 2, 3 and 4 byte code points
+        let text = "a\u{e9}\u{20ac}\u{1f980}z".as_bytes();
+        for i in 0..=text.len() {
+            for j in i..=text.len() {
+                let mut validator = Utf8Validator::new();
+                assert!(validator.feed(&text[..i]), "{} {}", i, j);
+                assert!(validator.feed(&text[i..j]), "{} {}", i, j);
+                assert!(validator.feed(&text[j..]), "{} {}", i, j);
+                assert!(validator.finish(), "{} {}", i, j);
+            }
+        }
+
+        // one byte per fragment
+        let mut validator = Utf8Validator::new();
+        assert!(text.iter().all(|b| validator.feed(&[*b])));
+        assert!(validator.finish());
+    }
+
+    #[test]
+    fn fails_fast() {
+        // an invalid continuation is caught before the message is finished
+        let mut validator = Utf8Validator::new();
+        assert!(validator.feed(b"foo\xE2\x82"));
+        assert!(!validator.feed(b"\x28bar"));
+
+        // as are bytes that can never appear
+        let mut validator = Utf8Validator::new();
+        assert!(!validator.feed(b"\xFF"));
+
+        // surrogates and code points above U+10FFFF are rejected on their first bytes
+        let mut validator = Utf8Validator::new();
+        assert!(!validator.feed(b"\xED\xA0"));
+        let mut validator = Utf8Validator::new();
+        assert!(!validator.feed(b"\xF4\x90"));
+    }
+
+    #[test]
+    fn incomplete_at_end() {
+        let mut validator = Utf8Validator::new();
+        assert!(validator.feed(b"foo\xF0\x9F\xA6"));
+        assert!(!validator.finish());
+
+        // and finish resets for the next message
+        assert!(validator.feed(b"bar"));
+        assert!(validator.finish());
+    }
+}
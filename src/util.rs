@@ -1,11 +1,25 @@
-use std::net::TcpStream;
+use std::{
+    net::{Shutdown, TcpStream},
+    time::Duration,
+};
 
-pub(crate) trait Stream {
+pub trait Stream {
     fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr>;
+    /// Shut down both halves of the underlying connection
+    fn shutdown(&self) -> std::io::Result<()>;
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()>;
 }
 
 impl Stream for TcpStream {
     fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr> {
         self.peer_addr()
     }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, dur)
+    }
 }